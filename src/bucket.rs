@@ -16,43 +16,102 @@
  */
 //! Leaky bucket algorithm is implemantation for mcatpcha using batch processing Everytime count
 //! is increased for an mcaptcha object, a decrement job is added to a batch that is scheduled to
-//! be executed at that mcaptcha object's expiry rate(MCaptcha.get_duration())
-use std::collections::HashMap;
+//! be executed at that mcaptcha object's expiry rate(MCaptcha.get_duration()). mCaptchas in
+//! sliding window mode don't use buckets, see [crate::sliding_window]
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
 use std::time::Duration;
 
-use redis_module::key::RedisKeyWritable;
+use lazy_static::lazy_static;
+use redis_module::key::{RedisKey, RedisKeyWritable};
 use redis_module::native_types::RedisType;
 use redis_module::raw::KeyType;
-use redis_module::{raw, Context};
-use redis_module::{NotifyEvent, RedisString};
+use redis_module::{raw, Context, ContextFlags};
+use redis_module::{KeysCursor, NotifyEvent, RedisString, RedisValue, REDIS_OK};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use crate::config::{log_debug, log_warning};
 use crate::errors::*;
 use crate::mcaptcha::MCaptcha;
 use crate::utils::*;
 use crate::*;
 
 /// Bucket type version, aka encoding version
-const REDIS_MCAPTCHA_BUCKET_TYPE_VERSION: i32 = 0;
+///
+/// - 0: JSON
+/// - 1: binary, fields are saved individually
+/// - 2: binary, bucket instant is in milliseconds
+const REDIS_MCAPTCHA_BUCKET_TYPE_VERSION: i32 = 2;
+
+/// most visitors that can be registered with a single `ADD_VISITORS`
+const MAX_VISITORS_BATCH: u64 = 1_000_000;
+
+lazy_static! {
+    /// pending timers, by bucket name. Timers outlive the keyspace when it is reloaded in-process,
+    /// so loaded buckets take over their pending timer instead of arming a new one
+    static ref BUCKET_TIMERS: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+}
 
 #[derive(Debug, PartialEq)]
-/// encoding formats for persistence
+/// encoding formats for persistence and command payloads
 pub enum Format {
     Json,
+    Bincode,
+    MsgPack,
+    Cbor,
 }
 
 impl Format {
+    /// parse format name sent by clients. [Format::Bincode] is only used for persistence
+    pub fn from_name(name: &str) -> CacheResult<Self> {
+        match name.to_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "msgpack" => Ok(Format::MsgPack),
+            "cbor" => Ok(Format::Cbor),
+            _ => Err(CacheError::new(format!("Unsupported format: {}", name))),
+        }
+    }
+
+    /// read optional `FORMAT <json|msgpack|cbor>` argument, defaults to [Format::Json]
+    pub fn from_args<I: Iterator<Item = RedisString>>(args: &mut I) -> CacheResult<Self> {
+        match args.next() {
+            None => Ok(Format::Json),
+            Some(arg) if arg.to_string_lossy().eq_ignore_ascii_case("FORMAT") => {
+                Self::from_name(&args.next_string()?)
+            }
+            Some(arg) => Err(CacheError::new(format!("Unknown argument: {}", arg))),
+        }
+    }
+
     #[inline]
-    pub fn parse_str<'a, T: Deserialize<'a>>(&self, data: &'a str) -> CacheResult<T> {
+    pub fn parse_str<T: DeserializeOwned>(&self, data: &str) -> CacheResult<T> {
+        self.parse_slice(data.as_bytes())
+    }
+
+    #[inline]
+    pub fn parse_slice<T: DeserializeOwned>(&self, data: &[u8]) -> CacheResult<T> {
         match self {
-            Format::Json => Ok(serde_json::from_str(data)?),
+            Format::Json => Ok(serde_json::from_slice(data)?),
+            Format::Bincode => Ok(bincode::deserialize(data)?),
+            Format::MsgPack => Ok(rmp_serde::from_slice(data)?),
+            Format::Cbor => Ok(ciborium::de::from_reader(data)?),
         }
     }
 
     #[inline]
-    pub fn from_str<'a, T: Deserialize<'a>>(&self, data: &'a str) -> CacheResult<T> {
-        let res = self.parse_str(data)?;
-        Ok(res)
+    pub fn to_vec<T: Serialize>(&self, data: &T) -> CacheResult<Vec<u8>> {
+        match self {
+            Format::Json => Ok(serde_json::to_vec(data)?),
+            Format::Bincode => Ok(bincode::serialize(data)?),
+            Format::MsgPack => Ok(rmp_serde::to_vec_named(data)?),
+            Format::Cbor => {
+                let mut buf = Vec::new();
+                ciborium::ser::into_writer(data, &mut buf)?;
+                Ok(buf)
+            }
+        }
     }
 }
 
@@ -60,20 +119,18 @@ impl Format {
 pub struct Bucket {
     /// timer ID
     timer: u64,
-    /// instant(seconds from UNIX_EPOCH) at which time bucket begins decrement process
+    /// instant(milliseconds from UNIX_EPOCH) at which time bucket begins decrement process
     bucket_instant: u64,
     /// a list of captcha keys that should be decremented during clean up
     decrement: HashMap<String, u32>,
 }
 
 impl Bucket {
-    /// Run when bucket timer expired at BUCKET_EXPIRY_OFFSET. Runs scheduled jobs in corresponding
+    /// Run when bucket timer expired at bucket expiry offset. Runs scheduled jobs in corresponding
     /// if they haven't already executed
     pub fn on_delete(ctx: &Context, _event_type: NotifyEvent, _event: &str, key_name: &str) {
-        // TODO: this callback is executed after the bucket is deleted. So all jobs scheduled within
-        // the bucket are lost. This means, we could end up with stagnent increments in mcaptcha objects
-        // Rather than setting a timer, use a safety, upon who's expiry, the bucket's callback(job
-        // runner) will be executed
+        // Timers don't survive restarts, they are re-armed by [Bucket::rearm_timers] once loading
+        // is complete. This is a fallback for buckets whose timers couldn't be re-armed.
         if !is_bucket_timer(key_name) {
             return;
         }
@@ -90,44 +147,43 @@ impl Bucket {
             bucket_name.as_bytes(),
         ));
         if bucket.key_type() == KeyType::Empty {
-            ctx.log_debug(&format!("Bucket doesn't exist: {}", key_name));
+            log_debug(ctx, &format!("Bucket doesn't exist: {}", key_name));
         } else {
-            Bucket::decrement_runner(ctx, &bucket);
+            drop(bucket);
+            Bucket::decrement(ctx, bucket_name.to_owned());
         }
     }
 
-    /// creates new bucket and sets off timer to go off at `duration`
+    /// creates new bucket and sets off timer to go off at `bucket_instant`, `delay` from now
     #[inline]
-    fn new(ctx: &Context, duration: u64) -> CacheResult<Self> {
-        let decrement = HashMap::with_capacity(HIT_PER_SECOND);
+    fn new(ctx: &Context, bucket_instant: u64, delay: Duration) -> Self {
+        let decrement = HashMap::with_capacity(config::hit_per_second());
 
-        let bucket_instant = get_bucket_instant(duration)?;
-        let timer = ctx.create_timer(
-            Duration::from_secs(duration),
-            Self::decrement,
-            bucket_instant,
-        );
+        let bucket_name = get_bucket_name(bucket_instant);
+        let timer = ctx.create_timer(delay, Self::run_timer, bucket_name.clone());
+        BUCKET_TIMERS.lock().unwrap().insert(bucket_name, timer);
+        metrics::TIMERS.incr();
 
-        let bucket = Bucket {
+        Bucket {
             timer,
             bucket_instant,
             decrement,
-        };
-        Ok(bucket)
+        }
     }
 
     /// decrement runner that decrements all registered counts _without_ cleaning after itself
     /// use [decrement] when you require auto cleanup. Internally, it calls this method.
+    /// Decremented mCaptchas are replicated, see [MCaptcha::replicate]
     #[inline]
-    fn decrement_runner(ctx: &Context, key: &RedisKeyWritable) {
+    fn decrement_runner(ctx: &Context, key: &RedisKeyWritable, bucket_name: &str) {
         match key.get_value::<Bucket>(&MCAPTCHA_BUCKET_TYPE) {
             Ok(Some(bucket)) => {
-                ctx.log_debug("entering loop hashmap");
+                log_debug(ctx, "entering loop hashmap");
                 for (captcha, count) in bucket.decrement.drain() {
-                    ctx.log_debug(&format!(
-                        "reading captcha: {} with decr count {}",
-                        &captcha, count
-                    ));
+                    log_debug(
+                        ctx,
+                        &format!("reading captcha: {} with decr count {}", &captcha, count),
+                    );
                     let stored_captcha = ctx.open_key_writable(&RedisString::create_from_slice(
                         ctx.ctx,
                         captcha.as_bytes(),
@@ -135,38 +191,55 @@ impl Bucket {
                     if stored_captcha.key_type() == KeyType::Empty {
                         continue;
                     }
-                    if let Ok(Some(captcha)) = MCaptcha::get_mut_mcaptcha(&stored_captcha) {
-                        captcha.decrement_visitor_by(count);
+                    if let Ok(Some(stored)) = MCaptcha::get_mut_mcaptcha(&stored_captcha) {
+                        stored.run_decrement(bucket.bucket_instant, bucket_name, count);
+                        metrics::DECREMENTS_EXECUTED.incr();
+                        if let Err(e) = stored.replicate(ctx, &captcha) {
+                            log_warning(ctx, &format!("can't replicate decrement: {}", e));
+                        }
                     }
                 }
             }
             _ => {
-                ctx.log_debug("bucket not found, can't decrement");
+                log_debug(ctx, "bucket not found, can't decrement");
             }
         }
     }
 
-    /// executes when timer goes off. Decrements all registered counts and cleans itself up
-    fn decrement(ctx: &Context, bucket_instant: u64) {
-        // get  bucket
-        let bucket_name = get_bucket_name(bucket_instant);
+    /// executes when timer goes off
+    fn run_timer(ctx: &Context, bucket_name: String) {
+        BUCKET_TIMERS.lock().unwrap().remove(&bucket_name);
+        metrics::TIMERS.decr();
+        Self::decrement(ctx, bucket_name);
+    }
 
+    /// decrements all registered counts and cleans itself up
+    fn decrement(ctx: &Context, bucket_name: String) {
+        let timer_name = get_timer_name_from_bucket_name(&bucket_name);
         let timer = ctx.open_key_writable(&RedisString::create_from_slice(
             ctx.ctx,
-            get_timer_name_from_bucket_name(&bucket_name).as_bytes(),
+            timer_name.as_bytes(),
         ));
         let _ = timer.delete();
 
-        ctx.log_debug(&format!("Bucket instant: {}", &bucket_instant));
+        log_debug(ctx, &format!("Bucket: {}", &bucket_name));
 
         let bucket = ctx.open_key_writable(&RedisString::create_from_slice(
             ctx.ctx,
             bucket_name.as_bytes(),
         ));
-        Bucket::decrement_runner(ctx, &bucket);
+        if bucket.key_type() != KeyType::Empty {
+            // replicas drop the bucket before they receive decremented mCaptchas, so that they
+            // no longer record it with them, see [MCaptcha::restore]
+            ctx.replicate("DEL", &[bucket_name.as_str(), timer_name.as_str()]);
+        }
+        Bucket::decrement_runner(ctx, &bucket, &bucket_name);
 
         if let Err(e) = bucket.delete() {
-            ctx.log_warning(&format!("enountered error while deleting hashmap: {:?}", e));
+            log_warning(
+                ctx,
+                &format!("enountered error while deleting hashmap: {:?}", e),
+            );
         }
 
         let timer = ctx.open_key_writable(&RedisString::create_from_slice(
@@ -174,16 +247,95 @@ impl Bucket {
             get_timer_name_from_bucket_name(&bucket_name).as_bytes(),
         ));
         if let Err(e) = timer.delete() {
-            ctx.log_warning(&format!(
-                "enountered error while deleting bucket tiemr: {:?}",
-                e
+            log_warning(
+                ctx,
+                &format!("enountered error while deleting bucket tiemr: {:?}", e),
+            );
+        }
+    }
+
+    /// Timers aren't persisted, so buckets loaded from RDB/AOF don't have one. Re-arms timers of
+    /// loaded buckets to go off at their bucket instant and runs overdue buckets immediately.
+    /// Buckets whose timer is still pending, when keyspace was reloaded in-process, keep it.
+    /// Pending decrements are recorded with their mCaptchas, which don't persist them. Replicas
+    /// don't arm timers, decrements are replicated by the primary
+    pub fn rearm_timers(ctx: &Context) {
+        // keys can't be deleted while scanning, so overdue buckets are run after the scan
+        let buckets = RefCell::new(Vec::new());
+        let collect = |_ctx: &Context, key_name: RedisString, _key: Option<&RedisKey>| {
+            let key_name = key_name.to_string_lossy();
+            if is_bucket(&key_name) {
+                buckets.borrow_mut().push(key_name);
+            }
+        };
+        let cursor = KeysCursor::new();
+        while cursor.scan(ctx, &collect) {}
+
+        let now = match now_millis() {
+            Ok(now) => now,
+            Err(e) => {
+                log_warning(ctx, &format!("can't re-arm bucket timers: {}", e));
+                return;
+            }
+        };
+
+        let replica = ctx.get_flags().contains(ContextFlags::SLAVE);
+        for bucket_name in buckets.into_inner() {
+            let key = ctx.open_key_writable(&RedisString::create_from_slice(
+                ctx.ctx,
+                bucket_name.as_bytes(),
             ));
+            let bucket = match key.get_value::<Bucket>(&MCAPTCHA_BUCKET_TYPE) {
+                Ok(Some(bucket)) => bucket,
+                _ => continue,
+            };
+            let pending = BUCKET_TIMERS.lock().unwrap().get(&bucket_name).copied();
+            if replica {
+                log_debug(
+                    ctx,
+                    &format!("not arming timer on replica: {}", bucket_name),
+                );
+            } else if let Some(timer) = pending {
+                log_debug(ctx, &format!("bucket has pending timer: {}", bucket_name));
+                bucket.timer = timer;
+            } else if bucket.bucket_instant <= now {
+                log_debug(ctx, &format!("running overdue bucket: {}", bucket_name));
+                drop(key);
+                Self::decrement(ctx, bucket_name);
+                continue;
+            } else {
+                log_debug(ctx, &format!("re-arming timer of bucket: {}", bucket_name));
+                bucket.timer = ctx.create_timer(
+                    Duration::from_millis(bucket.bucket_instant - now),
+                    Self::run_timer,
+                    bucket_name.clone(),
+                );
+                BUCKET_TIMERS
+                    .lock()
+                    .unwrap()
+                    .insert(bucket_name.clone(), bucket.timer);
+                metrics::TIMERS.incr();
+            }
+            for captcha_name in bucket.decrement.keys() {
+                let captcha = ctx.open_key_writable(&RedisString::create_from_slice(
+                    ctx.ctx,
+                    captcha_name.as_bytes(),
+                ));
+                if let Ok(Some(captcha)) = MCaptcha::get_mut_mcaptcha(&captcha) {
+                    captcha.schedule_decrement(bucket.bucket_instant, &bucket_name);
+                }
+            }
         }
     }
 
-    /// increments count of key = captcha and registers for auto decrement
+    /// increments count of key = captcha by `count` and registers for auto decrement
     #[inline]
-    fn increment(ctx: &Context, captcha: &str) -> CacheResult<String> {
+    fn increment(
+        ctx: &Context,
+        captcha: &str,
+        count: u32,
+        format: &Format,
+    ) -> CacheResult<Vec<u8>> {
         let captcha_name = get_captcha_key(&captcha);
         //        ctx.log_debug(&captcha_name);
         // increment
@@ -191,37 +343,91 @@ impl Bucket {
             ctx.ctx,
             captcha_name.as_bytes(),
         ));
-        ctx.log_debug("loading mcaptcha");
+        log_debug(ctx, "loading mcaptcha");
         let captcha = MCaptcha::get_mut_mcaptcha(&captcha)?;
 
-        ctx.log_debug("loaded mcaptcha");
+        log_debug(ctx, "loaded mcaptcha");
         if captcha.is_none() {
             return Err(CacheError::new("Captcha not found".into()));
         }
         let captcha = captcha.unwrap();
-        ctx.log_debug(&format!(
-            "current visitor count: {}",
-            captcha.get_visitors()
-        ));
-        captcha.add_visitor();
+        log_debug(
+            ctx,
+            &format!("current visitor count: {}", captcha.get_visitors()),
+        );
+        captcha.register_visitors(now_millis()?, count)?;
+        metrics::VISITORS_ADDED.add(count.into());
         let res = captcha.get_add_visitor_result();
-        let res = serde_json::to_string(&res)?;
+        let res = format.to_vec(&res)?;
+
+        log_debug(ctx, "visitor added");
+        if !captcha.is_sliding_window() {
+            let duration_ms = captcha.get_duration_ms();
+            let (bucket_instant, bucket_name) =
+                Self::increment_by(ctx, (captcha_name.clone(), duration_ms), count)?;
+            captcha.schedule_decrement(bucket_instant, &bucket_name);
+            // effects depend on the clock and on the node, buckets are named after it, so they are
+            // replicated instead of the command
+            captcha.replicate(ctx, &captcha_name)?;
+            Self::replicate(ctx, &bucket_name)?;
+        } else {
+            captcha.replicate(ctx, &captcha_name)?;
+        }
 
-        ctx.log_debug("visitor added");
-        let duration = captcha.get_duration();
+        Ok(res)
+    }
 
-        Self::increment_by(ctx, (captcha_name, duration), 1)?;
+    /// replicate bucket `bucket_name` as `RESTORE_BUCKET`, along with its timer key
+    fn replicate(ctx: &Context, bucket_name: &str) -> CacheResult<()> {
+        let key = ctx.open_key(&RedisString::create_from_slice(
+            ctx.ctx,
+            bucket_name.as_bytes(),
+        ));
+        if let Some(bucket) = key.get_value::<Bucket>(&MCAPTCHA_BUCKET_TYPE)? {
+            let data = serde_json::to_string(bucket)?;
+            ctx.replicate(
+                "MCAPTCHA_CACHE.RESTORE_BUCKET",
+                &[bucket_name, data.as_str()],
+            );
+            let expiry = bucket.bucket_instant + config::bucket_expiry_offset() * 1000;
+            ctx.replicate(
+                "SET",
+                &[
+                    get_timer_name_from_bucket_name(bucket_name).as_str(),
+                    "1",
+                    "PXAT",
+                    expiry.to_string().as_str(),
+                ],
+            );
+        }
+        Ok(())
+    }
 
-        Ok(res)
+    /// true if bucket `bucket_name` has pending decrements of captcha `captcha_name`
+    pub fn is_pending(ctx: &Context, bucket_name: &str, captcha_name: &str) -> bool {
+        let key = ctx.open_key(&RedisString::create_from_slice(
+            ctx.ctx,
+            bucket_name.as_bytes(),
+        ));
+        matches!(
+            key.get_value::<Bucket>(&MCAPTCHA_BUCKET_TYPE),
+            Ok(Some(bucket)) if bucket.decrement.contains_key(captcha_name)
+        )
     }
 
-    /// open bucket, set decrement by specified number
+    /// open bucket, set decrement by specified number. Duration is in milliseconds. Returns
+    /// instant and name of the bucket, to be recorded with [MCaptcha::schedule_decrement]
     pub fn increment_by(
         ctx: &Context,
-        (captcha_name, duration): (String, u64),
+        (captcha_name, duration_ms): (String, u64),
         increment_by: u32,
-    ) -> CacheResult<()> {
-        let bucket_instant = get_bucket_instant(duration)?;
+    ) -> CacheResult<(u64, String)> {
+        let resolution = config::bucket_resolution();
+        let expiry_offset = config::bucket_expiry_offset();
+        // increments that fall in the same slot of `resolution` seconds share a bucket and a
+        // timer, decrements are delayed by at most a slot
+        let now = now_millis()?;
+        let bucket_instant = get_bucket_instant(now, duration_ms, resolution * 1000);
         let bucket_name = get_bucket_name(bucket_instant);
 
         //        ctx.log_debug(&format!("Bucket name: {}", &bucket_name));
@@ -234,26 +440,28 @@ impl Bucket {
 
         match bucket.get_value::<Bucket>(&MCAPTCHA_BUCKET_TYPE)? {
             Some(bucket) => match bucket.decrement.get_mut(&captcha_name) {
-                Some(count) => *count += increment_by,
+                Some(count) => *count = count.saturating_add(increment_by),
                 None => {
-                    bucket.decrement.insert(captcha_name, 1);
+                    bucket.decrement.insert(captcha_name, increment_by);
                 }
             },
 
             None => {
-                let mut counter = Bucket::new(ctx, duration)?;
-                counter.decrement.insert(captcha_name, 1);
+                let delay = Duration::from_millis(bucket_instant - now);
+                let mut counter = Bucket::new(ctx, bucket_instant, delay);
+                counter.decrement.insert(captcha_name, increment_by);
                 bucket.set_value(&MCAPTCHA_BUCKET_TYPE, counter)?;
+                metrics::BUCKETS.incr();
                 let timer = ctx.open_key_writable(&RedisString::create_from_slice(
                     ctx.ctx,
                     get_timer_name_from_bucket_name(&bucket_name).as_bytes(),
                 ));
                 timer.write("1")?;
-                timer.set_expire(Duration::from_secs(duration + BUCKET_EXPIRY_OFFSET))?;
+                timer.set_expire(delay + Duration::from_secs(expiry_offset))?;
             }
         };
 
-        Ok(())
+        Ok((bucket_instant, bucket_name))
     }
 
     /// Create new counter
@@ -261,10 +469,149 @@ impl Bucket {
         let mut args = args.into_iter().skip(1);
         // mcaptcha captcha key name
         let key_name = args.next_string()?;
+        let format = Format::from_args(&mut args)?;
         // expiry
-        let res = Self::increment(ctx, &key_name)?;
+        let res = Self::increment(ctx, &key_name, 1, &format)?;
         Ok(res.into())
     }
+
+    /// Register a batch of visitors with a single decrement
+    pub fn add_visitors(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+        let mut args = args.into_iter().skip(1);
+        // mcaptcha captcha key name
+        let key_name = args.next_string()?;
+        let count = args.next_u64()?;
+        if count == 0 || count > MAX_VISITORS_BATCH {
+            return Err(CacheError::new(format!("Invalid visitor count: {}", count)).into());
+        }
+        let format = Format::from_args(&mut args)?;
+        let res = Self::increment(ctx, &key_name, count as u32, &format)?;
+        Ok(res.into())
+    }
+
+    /// move pending decrements of captcha `from` to captcha `to`, in `scheduled` buckets of
+    /// `from`
+    pub fn rename_captcha(
+        ctx: &Context,
+        scheduled: &BTreeSet<(u64, String)>,
+        from: &str,
+        to: &str,
+    ) -> CacheResult<()> {
+        for (_, bucket_name) in scheduled {
+            let key = ctx.open_key_writable(&RedisString::create_from_slice(
+                ctx.ctx,
+                bucket_name.as_bytes(),
+            ));
+            if let Some(bucket) = key.get_value::<Bucket>(&MCAPTCHA_BUCKET_TYPE)? {
+                if let Some(count) = bucket.decrement.remove(from) {
+                    let pending = bucket.decrement.entry(to.to_owned()).or_insert(0);
+                    *pending = pending.saturating_add(count);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// remove pending decrements of captcha from its `scheduled` buckets. Returns number of
+    /// visitors whose decrements were removed
+    pub fn purge_captcha(
+        ctx: &Context,
+        scheduled: &BTreeSet<(u64, String)>,
+        captcha_name: &str,
+    ) -> CacheResult<u64> {
+        let mut purged = 0;
+        for (_, bucket_name) in scheduled {
+            let key = ctx.open_key_writable(&RedisString::create_from_slice(
+                ctx.ctx,
+                bucket_name.as_bytes(),
+            ));
+            if let Some(bucket) = key.get_value::<Bucket>(&MCAPTCHA_BUCKET_TYPE)? {
+                if let Some(count) = bucket.decrement.remove(captcha_name) {
+                    purged += count as u64;
+                }
+            }
+        }
+        Ok(purged)
+    }
+
+    /// Pending decrements of a captcha, as `(bucket instant, count)` pairs sorted by bucket
+    /// instant. Bucket instants are in milliseconds from UNIX_EPOCH. Only buckets recorded with
+    /// the captcha are read
+    pub fn get_scheduled_decrements(
+        ctx: &Context,
+        captcha_name: &str,
+    ) -> CacheResult<Vec<(u64, u32)>> {
+        let captcha = ctx.open_key(&RedisString::create_from_slice(
+            ctx.ctx,
+            captcha_name.as_bytes(),
+        ));
+        let captcha = match MCaptcha::get_mcaptcha(&captcha)? {
+            Some(captcha) => captcha,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut scheduled = Vec::with_capacity(captcha.get_scheduled().len());
+        for (bucket_instant, bucket_name) in captcha.get_scheduled() {
+            let key = ctx.open_key(&RedisString::create_from_slice(
+                ctx.ctx,
+                bucket_name.as_bytes(),
+            ));
+            if let Some(bucket) = key.get_value::<Bucket>(&MCAPTCHA_BUCKET_TYPE)? {
+                if let Some(count) = bucket.decrement.get(captcha_name) {
+                    scheduled.push((*bucket_instant, *count));
+                }
+            }
+        }
+        Ok(scheduled)
+    }
+
+    /// Pending decrements of a captcha, as `[bucket instant, count]` pairs sorted by bucket
+    /// instant. Bucket instants are in milliseconds from UNIX_EPOCH
+    pub fn scheduled_decrements(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+        let mut args = args.into_iter().skip(1);
+        let captcha_name = get_captcha_key(&args.next_string()?);
+        args.done()?;
+
+        let scheduled = Self::get_scheduled_decrements(ctx, &captcha_name)?
+            .into_iter()
+            .map(|(bucket_instant, count)| {
+                RedisValue::Array(vec![
+                    RedisValue::Integer(bucket_instant as i64),
+                    RedisValue::Integer(count.into()),
+                ])
+            })
+            .collect();
+        Ok(RedisValue::Array(scheduled))
+    }
+
+    /// Restore bucket from its serialized form. Emitted during AOF rewrite and replicated by
+    /// [Bucket::replicate]. Its decrements are recorded with their mCaptchas, timers are armed
+    /// once loading is complete, see [Bucket::rearm_timers]
+    pub fn restore(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+        let mut args = args.into_iter().skip(1);
+        let key_name = args.next_string()?;
+        let data = args.next_string()?;
+        let bucket: Bucket = Format::Json.parse_str(&data)?;
+
+        let key = ctx.open_key_writable(&RedisString::create_from_slice(
+            ctx.ctx,
+            key_name.as_bytes(),
+        ));
+        check_restore(ctx, &key)?;
+        for captcha_name in bucket.decrement.keys() {
+            let captcha = ctx.open_key_writable(&RedisString::create_from_slice(
+                ctx.ctx,
+                captcha_name.as_bytes(),
+            ));
+            if let Some(captcha) = MCaptcha::get_mut_mcaptcha(&captcha)? {
+                captcha.schedule_decrement(bucket.bucket_instant, &key_name);
+            }
+        }
+        key.set_value(&MCAPTCHA_BUCKET_TYPE, bucket)?;
+        metrics::BUCKETS.incr();
+        ctx.replicate_verbatim();
+        REDIS_OK
+    }
 }
 
 pub static MCAPTCHA_BUCKET_TYPE: RedisType = RedisType::new(
@@ -274,13 +621,13 @@ pub static MCAPTCHA_BUCKET_TYPE: RedisType = RedisType::new(
         version: raw::REDISMODULE_TYPE_METHOD_VERSION as u64,
         rdb_load: Some(type_methods::rdb_load),
         rdb_save: Some(type_methods::rdb_save),
-        aof_rewrite: None,
+        aof_rewrite: Some(type_methods::aof_rewrite),
         free: Some(type_methods::free),
+        mem_usage: Some(type_methods::mem_usage),
+        digest: Some(type_methods::digest),
 
         // Currently unused by Redis
-        mem_usage: None,
         mem_usage2: None,
-        digest: None,
 
         // Aux data
         aux_load: None,
@@ -292,14 +639,16 @@ pub static MCAPTCHA_BUCKET_TYPE: RedisType = RedisType::new(
         free_effort2: None,
         unlink: None,
         unlink2: None,
-        copy: None,
+        copy: Some(type_methods::copy),
         copy2: None,
-        defrag: None,
+        defrag: Some(type_methods::defrag),
     },
 );
 
 pub mod type_methods {
+    use std::mem::size_of;
     use std::os::raw::c_void;
+    use std::ptr;
 
     use libc::c_int;
 
@@ -307,30 +656,145 @@ pub mod type_methods {
 
     #[allow(non_snake_case, unused)]
     pub extern "C" fn rdb_load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> *mut c_void {
-        let bucket = match encver {
-            0 => {
-                let data = raw::load_string(rdb).unwrap().to_string();
-                let bucket: Bucket = Format::Json.from_str(&data).unwrap();
-                bucket
+        let value = match load(rdb, encver) {
+            Ok(bucket) => Box::into_raw(Box::new(bucket)) as *mut c_void,
+            Err(e) => {
+                quarantine::on_load_error(rdb, &MCAPTCHA_BUCKET_TYPE, "bucket", encver, e, || {
+                    Bucket {
+                        timer: 0,
+                        bucket_instant: 0,
+                        decrement: HashMap::default(),
+                    }
+                })
             }
-            _ => panic!("Can't load bucket from old redis RDB, encver: {}", encver,),
         };
+        if !value.is_null() {
+            metrics::BUCKETS.incr();
+        }
+        value
+    }
 
-        //        if bucket.
-        Box::into_raw(Box::new(bucket)) as *mut c_void
+    fn load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> CacheResult<Bucket> {
+        match encver {
+            0 => {
+                let mut bucket: Bucket =
+                    Format::Json.parse_slice(raw::load_string(rdb)?.as_slice())?;
+                bucket.bucket_instant *= 1000;
+                Ok(bucket)
+            }
+            1 => {
+                let mut bucket = load_fields(rdb)?;
+                bucket.bucket_instant *= 1000;
+                Ok(bucket)
+            }
+            2 => load_fields(rdb),
+            _ => Err(CacheError::new(format!(
+                "Can't load bucket from unknown encver: {}",
+                encver
+            ))),
+        }
     }
 
     pub unsafe extern "C" fn free(value: *mut c_void) {
         let val = value as *mut Bucket;
-        Box::from_raw(val);
+        drop(Box::from_raw(val));
+        metrics::BUCKETS.decr();
+    }
+
+    /// estimate of memory used by a bucket, including its decrement map
+    pub unsafe extern "C" fn mem_usage(value: *const c_void) -> usize {
+        let bucket = &*(value as *const Bucket);
+        let entries = bucket.decrement.capacity() * (size_of::<(String, u32)>() + 1);
+        let captchas: usize = bucket.decrement.keys().map(|c| c.capacity()).sum();
+        size_of::<Bucket>() + entries + captchas
+    }
+
+    /// bucket instant followed by decrement entries, sorted by captcha name so that digest
+    /// doesn't depend on HashMap iteration order. Timer IDs are node local and are left out
+    pub unsafe extern "C" fn digest(md: *mut raw::RedisModuleDigest, value: *mut c_void) {
+        let bucket = &*(value as *mut Bucket);
+        digest_add_u64(md, bucket.bucket_instant);
+        let mut decrement: Vec<(&String, &u32)> = bucket.decrement.iter().collect();
+        decrement.sort_unstable();
+        for (captcha, count) in decrement {
+            digest_add_str(md, captcha);
+            digest_add_u64(md, *count as u64);
+        }
+        digest_end_sequence(md);
+    }
+
+    /// Buckets are named after the instant at which their timer runs. A copy wouldn't have a
+    /// timer and would never be run, so copying them is refused
+    pub unsafe extern "C" fn copy(
+        _fromkey: *mut raw::RedisModuleString,
+        _tokey: *mut raw::RedisModuleString,
+        _value: *const c_void,
+    ) -> *mut c_void {
+        ptr::null_mut()
+    }
+
+    /// Moves the boxed bucket. Keys of decrement map can't be moved in place, so once the bucket
+    /// is moved, map is rebuilt with captcha names moved by [defrag_string]
+    pub unsafe extern "C" fn defrag(
+        ctx: *mut raw::RedisModuleDefragCtx,
+        _key: *mut raw::RedisModuleString,
+        value: *mut *mut c_void,
+    ) -> c_int {
+        let moved = defrag_alloc(ctx, *value);
+        if moved.is_null() {
+            return 0;
+        }
+        *value = moved;
+        let bucket = &mut *(*value as *mut Bucket);
+        if !bucket.decrement.is_empty() {
+            bucket.decrement = std::mem::take(&mut bucket.decrement)
+                .into_iter()
+                .map(|(mut captcha, count)| {
+                    defrag_string(ctx, &mut captcha);
+                    (captcha, count)
+                })
+                .collect();
+        }
+        0
     }
 
     #[allow(non_snake_case, unused)]
     pub unsafe extern "C" fn rdb_save(rdb: *mut raw::RedisModuleIO, value: *mut c_void) {
+        let bucket = &*(value as *mut Bucket);
+        raw::save_unsigned(rdb, bucket.bucket_instant);
+        raw::save_unsigned(rdb, bucket.decrement.len() as u64);
+        for (captcha, count) in bucket.decrement.iter() {
+            raw::save_string(rdb, captcha);
+            raw::save_unsigned(rdb, *count as u64);
+        }
+    }
+
+    /// load bucket saved by [rdb_save]. Timer IDs don't survive restarts, so they aren't saved
+    fn load_fields(rdb: *mut raw::RedisModuleIO) -> CacheResult<Bucket> {
+        let bucket_instant = raw::load_unsigned(rdb)?;
+        let len = raw::load_unsigned(rdb)? as usize;
+        let mut decrement = HashMap::with_capacity(len.min(config::hit_per_second()));
+        for _ in 0..len {
+            let captcha = raw::load_string(rdb)?.to_string_lossy();
+            let count = raw::load_unsigned(rdb)? as u32;
+            decrement.insert(captcha, count);
+        }
+        Ok(Bucket {
+            timer: 0,
+            bucket_instant,
+            decrement,
+        })
+    }
+
+    pub unsafe extern "C" fn aof_rewrite(
+        aof: *mut raw::RedisModuleIO,
+        key: *mut raw::RedisModuleString,
+        value: *mut c_void,
+    ) {
         let bucket = &*(value as *mut Bucket);
         match &serde_json::to_string(bucket) {
-            Ok(string) => raw::save_string(rdb, string),
-            Err(e) => eprintln!("error while rdb_save: {}", e),
+            Ok(string) => emit_aof(aof, "MCAPTCHA_CACHE.RESTORE_BUCKET", key, string),
+            Err(e) => panic!("error while aof_rewrite: {}", e),
         }
     }
 }
@@ -14,28 +14,94 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
 use std::time::Duration;
 
+use lazy_static::lazy_static;
 use libmcaptcha::cache::AddChallenge;
 use libmcaptcha::master::AddVisitorResult;
+use mcaptcha_pow_sha256::{Config, PoW, PoWBuilder};
 use redis_module::native_types::RedisType;
 use redis_module::raw::KeyType;
+use redis_module::redisvalue::RedisValueKey;
 use redis_module::NextArg;
 use redis_module::RedisResult;
 use redis_module::RedisString;
+use redis_module::RedisValue;
 use redis_module::REDIS_OK;
 use redis_module::{raw, Context};
 use serde::{Deserialize, Serialize};
 
 use crate::bucket::Format;
+use crate::config;
 use crate::errors::*;
+use crate::mcaptcha::MCaptcha;
+use crate::metrics;
+use crate::quarantine;
 use crate::utils::*;
 
-const MCAPTCHA_CHALLENGE_VERSION: i32 = 0;
+/// Challenge type version, aka encoding version
+///
+/// - 0: JSON
+/// - 1: binary, fields are saved individually
+const MCAPTCHA_CHALLENGE_VERSION: i32 = 1;
 
-#[derive(Serialize, Deserialize)]
+lazy_static! {
+    /// outstanding challenges by captcha, with the instant, in milliseconds from UNIX_EPOCH, by
+    /// which they expire. Challenges of a captcha are found without scanning the keyspace.
+    /// Expired challenges are pruned as challenges are added to their captcha
+    static ref CHALLENGE_INDEX: Mutex<HashMap<String, HashMap<String, u64>>> =
+        Mutex::new(HashMap::new());
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Challenge(AddVisitorResult);
 
+/// outcome of challenge verification
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Verdict {
+    Valid,
+    NotFound,
+    DifficultyTooLow,
+    InvalidProof,
+    InsufficientWork,
+}
+
+impl Verdict {
+    fn name(&self) -> &'static str {
+        match self {
+            Verdict::Valid => "ok",
+            Verdict::NotFound => "not_found",
+            Verdict::DifficultyTooLow => "difficulty_too_low",
+            Verdict::InvalidProof => "invalid_proof",
+            Verdict::InsufficientWork => "insufficient_work",
+        }
+    }
+
+    /// verdict map of challenge verification commands
+    fn reply(self, stored: Option<&Challenge>) -> RedisValue {
+        let optional =
+            |val: Option<u64>| val.map_or(RedisValue::Null, |v| RedisValue::Integer(v as i64));
+        let resp: BTreeMap<RedisValueKey, RedisValue> = [
+            (
+                "valid",
+                RedisValue::Integer((self == Verdict::Valid).into()),
+            ),
+            ("reason", RedisValue::SimpleStringStatic(self.name())),
+            (
+                "difficulty_factor",
+                optional(stored.map(|c| c.0.difficulty_factor as u64)),
+            ),
+            ("duration", optional(stored.map(|c| c.0.duration))),
+        ]
+        .into_iter()
+        .map(|(name, val)| (RedisValueKey::String(name.into()), val))
+        .collect();
+        RedisValue::OrderedMap(resp)
+    }
+}
+
 impl Challenge {
     pub fn new(duration: u64, difficulty: u32) -> Self {
         Self(AddVisitorResult {
@@ -44,11 +110,49 @@ impl Challenge {
         })
     }
 
+    /// record challenge `challenge_name` of `captcha`, that expires `duration` seconds from
+    /// `now`
+    fn index(captcha: &str, challenge_name: String, duration: u64, now: u64) {
+        let mut index = CHALLENGE_INDEX.lock().unwrap();
+        let challenges = index.entry(captcha.to_owned()).or_default();
+        challenges.retain(|_, expiry| *expiry > now);
+        challenges.insert(
+            challenge_name,
+            now.saturating_add(duration.saturating_mul(1000)),
+        );
+    }
+
+    /// record challenge stored under `key`, when it is loaded, restored or copied. Its TTL isn't
+    /// known, it expires at most its duration from now
+    fn index_key(challenge_name: &str, challenge: &Challenge) {
+        if let Some(captcha) = get_captcha_from_challenge_name(challenge_name) {
+            let now = now_millis().unwrap_or_default();
+            Self::index(
+                captcha,
+                challenge_name.to_owned(),
+                challenge.0.duration,
+                now,
+            );
+        }
+    }
+
+    /// forget challenge `challenge_name` of `captcha`, once it is deleted
+    fn unindex(captcha: &str, challenge_name: &str) {
+        let mut index = CHALLENGE_INDEX.lock().unwrap();
+        if let Some(challenges) = index.get_mut(captcha) {
+            challenges.remove(challenge_name);
+            if challenges.is_empty() {
+                index.remove(captcha);
+            }
+        }
+    }
+
     pub fn create_challenge(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
         let mut args = args.into_iter().skip(1);
         let captcha = args.next_string()?;
-        let json = args.next_string()?;
-        let add_challenge: AddChallenge = Format::Json.from_str(&json)?;
+        let payload = args.next_arg()?;
+        let format = Format::from_args(&mut args)?;
+        let add_challenge: AddChallenge = format.parse_slice(payload.as_slice())?;
 
         let challenge_name = get_challenge_name(&captcha, &add_challenge.challenge);
 
@@ -59,14 +163,34 @@ impl Challenge {
         if key.key_type() != KeyType::Empty {
             return Err(CacheError::DuplicateChallenge.into());
         }
-        let challenge = Self::new(add_challenge.duration, add_challenge.difficulty as u32);
+        let challenge = Self::new(add_challenge.duration, add_challenge.difficulty);
 
         key.set_value(&MCAPTCHA_CHALLENGE_TYPE, challenge)?;
+        metrics::CHALLENGES.incr();
         key.set_expire(Duration::from_secs(add_challenge.duration))?;
-
+        Self::index(
+            &captcha,
+            challenge_name,
+            add_challenge.duration,
+            now_millis()?,
+        );
+        Self::update_stats(ctx, &captcha, MCaptcha::challenge_issued)?;
+        ctx.replicate_verbatim();
         REDIS_OK
     }
 
+    /// update statistics of `captcha`, if it exists
+    fn update_stats(ctx: &Context, captcha: &str, update: fn(&mut MCaptcha)) -> CacheResult<()> {
+        let key = ctx.open_key_writable(&RedisString::create_from_slice(
+            ctx.ctx,
+            get_captcha_key(&captcha).as_bytes(),
+        ));
+        if let Some(mcaptcha) = MCaptcha::get_mut_mcaptcha(&key)? {
+            update(mcaptcha);
+        }
+        Ok(())
+    }
+
     pub fn delete_challenge(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
         let mut args = args.into_iter().skip(1);
         let captcha = args.next_string()?;
@@ -82,14 +206,39 @@ impl Challenge {
             Err(CacheError::ChallengeNotFound.into())
         } else {
             key.delete()?;
+            Self::unindex(&captcha, &challenge_name);
+            ctx.replicate_verbatim();
             REDIS_OK
         }
     }
 
+    /// delete all outstanding challenges of captcha. Returns number of challenges deleted
+    pub fn delete_all(ctx: &Context, captcha: &str) -> CacheResult<usize> {
+        let challenges = CHALLENGE_INDEX
+            .lock()
+            .unwrap()
+            .remove(captcha)
+            .unwrap_or_default();
+
+        let mut deleted = 0;
+        for challenge_name in challenges.into_keys() {
+            let key = ctx.open_key_writable(&RedisString::create_from_slice(
+                ctx.ctx,
+                challenge_name.as_bytes(),
+            ));
+            if key.get_value::<Self>(&MCAPTCHA_CHALLENGE_TYPE)?.is_some() {
+                key.delete()?;
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
     pub fn get_challenge(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
         let mut args = args.into_iter().skip(1);
         let captcha = args.next_string()?;
         let challenge = args.next_string()?;
+        let format = Format::from_args(&mut args)?;
 
         let challenge_name = get_challenge_name(&captcha, &challenge);
 
@@ -102,13 +251,129 @@ impl Challenge {
         }
         match key.get_value::<Self>(&MCAPTCHA_CHALLENGE_TYPE)? {
             Some(challenge) => {
-                let resp = serde_json::to_string(&challenge)?;
+                let resp = format.to_vec(&challenge)?;
                 key.delete()?;
+                Self::unindex(&captcha, &challenge_name);
+                Self::update_stats(ctx, &captcha, MCaptcha::challenge_verified)?;
+                ctx.replicate_verbatim();
                 Ok(resp.into())
             }
             None => Err(CacheError::ChallengeNotFound.into()),
         }
     }
+
+    /// Verify challenge of captcha against expected difficulty factor. A challenge that is found
+    /// is consumed, whether it passes verification or not, so it can't be replayed. Replies with
+    /// a verdict map
+    pub fn verify_challenge(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+        let mut args = args.into_iter().skip(1);
+        let captcha = args.next_string()?;
+        let challenge = args.next_string()?;
+        let difficulty = args.next_u64()?;
+        args.done()?;
+
+        let challenge_name = get_challenge_name(&captcha, &challenge);
+        let key = ctx.open_key_writable(&RedisString::create_from_slice(
+            ctx.ctx,
+            challenge_name.as_bytes(),
+        ));
+        let stored = key.get_value::<Self>(&MCAPTCHA_CHALLENGE_TYPE)?.cloned();
+
+        let verdict = match &stored {
+            None => Verdict::NotFound,
+            Some(stored) if (stored.0.difficulty_factor as u64) < difficulty => {
+                Verdict::DifficultyTooLow
+            }
+            Some(_) => Verdict::Valid,
+        };
+        if stored.is_some() {
+            key.delete()?;
+            Self::unindex(&captcha, &challenge_name);
+        }
+        if verdict == Verdict::Valid {
+            Self::update_stats(ctx, &captcha, MCaptcha::challenge_verified)?;
+        }
+        ctx.replicate_verbatim();
+        Ok(verdict.reply(stored.as_ref()))
+    }
+
+    /// check proof of work computed over challenge string against stored difficulty factor
+    fn check_pow(&self, salt: &str, challenge: &str, pow: &PoW<String>) -> Verdict {
+        let config = Config {
+            salt: salt.to_owned(),
+        };
+        if !config.is_valid_proof(pow, &challenge.to_owned()) {
+            Verdict::InvalidProof
+        // difficulty factor of 0 can't be checked, any work is sufficient for it
+        } else if !config.is_sufficient_difficulty(pow, self.0.difficulty_factor.max(1)) {
+            Verdict::InsufficientWork
+        } else {
+            Verdict::Valid
+        }
+    }
+
+    /// Verify proof of work submitted for challenge of captcha, with the same algorithm as
+    /// mCaptcha. Challenge is consumed only when verification succeeds. Replies with a verdict
+    /// map
+    pub fn verify_pow(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+        let mut args = args.into_iter().skip(1);
+        let captcha = args.next_string()?;
+        let challenge = args.next_string()?;
+        let nonce = args.next_u64()?;
+        let result = args.next_string()?;
+        args.done()?;
+
+        let salt = config::pow_salt();
+        if salt.is_empty() {
+            return Err(CacheError::new(
+                "PoW verification is disabled, pow-salt is not set".into(),
+            )
+            .into());
+        }
+        let pow = PoWBuilder::default()
+            .nonce(nonce)
+            .result(result)
+            .build()
+            .map_err(|e| CacheError::new(e.to_string()))?;
+
+        let challenge_name = get_challenge_name(&captcha, &challenge);
+        let key = ctx.open_key_writable(&RedisString::create_from_slice(
+            ctx.ctx,
+            challenge_name.as_bytes(),
+        ));
+        let stored = key.get_value::<Self>(&MCAPTCHA_CHALLENGE_TYPE)?.cloned();
+
+        let verdict = match &stored {
+            None => Verdict::NotFound,
+            Some(stored) => stored.check_pow(&salt, &challenge, &pow),
+        };
+        if verdict == Verdict::Valid {
+            key.delete()?;
+            Self::unindex(&captcha, &challenge_name);
+            Self::update_stats(ctx, &captcha, MCaptcha::challenge_verified)?;
+        }
+        ctx.replicate_verbatim();
+        Ok(verdict.reply(stored.as_ref()))
+    }
+
+    /// Restore challenge from its serialized form. Emitted during AOF rewrite
+    pub fn restore(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+        let mut args = args.into_iter().skip(1);
+        let key_name = args.next_string()?;
+        let data = args.next_string()?;
+        let challenge = Challenge(Format::Json.parse_str(&data)?);
+
+        let key = ctx.open_key_writable(&RedisString::create_from_slice(
+            ctx.ctx,
+            key_name.as_bytes(),
+        ));
+        check_restore(ctx, &key)?;
+        Self::index_key(&key_name, &challenge);
+        key.set_value(&MCAPTCHA_CHALLENGE_TYPE, challenge)?;
+        metrics::CHALLENGES.incr();
+        ctx.replicate_verbatim();
+        REDIS_OK
+    }
 }
 
 pub static MCAPTCHA_CHALLENGE_TYPE: RedisType = RedisType::new(
@@ -118,13 +383,13 @@ pub static MCAPTCHA_CHALLENGE_TYPE: RedisType = RedisType::new(
         version: raw::REDISMODULE_TYPE_METHOD_VERSION as u64,
         rdb_load: Some(type_methods::rdb_load),
         rdb_save: Some(type_methods::rdb_save),
-        aof_rewrite: None,
+        aof_rewrite: Some(type_methods::aof_rewrite),
         free: Some(type_methods::free),
+        mem_usage: Some(type_methods::mem_usage),
+        digest: Some(type_methods::digest),
 
         // Currently unused by Redis
-        mem_usage: None,
         mem_usage2: None,
-        digest: None,
 
         // Aux data
         aux_load: None,
@@ -136,13 +401,14 @@ pub static MCAPTCHA_CHALLENGE_TYPE: RedisType = RedisType::new(
         free_effort2: None,
         unlink: None,
         unlink2: None,
-        copy: None,
+        copy: Some(type_methods::copy),
         copy2: None,
         defrag: None,
     },
 );
 
 pub mod type_methods {
+    use std::mem::size_of;
     use std::os::raw::c_void;
 
     use libc::c_int;
@@ -151,36 +417,151 @@ pub mod type_methods {
 
     #[allow(non_snake_case, unused)]
     pub extern "C" fn rdb_load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> *mut c_void {
-        let challenge = match encver {
-            0 => {
-                let data = raw::load_string(rdb).unwrap().to_string();
-                let challenge: Result<AddVisitorResult, CacheError> = Format::Json.from_str(&data);
-                if challenge.is_err() {
-                    panic!(
-                        "Can't load Challenge from old redis RDB, error while serde {}, data received: {}",
-                        challenge.err().unwrap(),
-                        data
-                    );
+        let value = match load(rdb, encver) {
+            Ok(challenge) => {
+                let key = unsafe { raw::RedisModule_GetKeyNameFromIO.unwrap()(rdb) };
+                if !key.is_null() {
+                    let key = String::from_utf8_lossy(RedisString::string_as_slice(key));
+                    Challenge::index_key(&key, &challenge);
                 }
-                Challenge(challenge.unwrap())
+                Box::into_raw(Box::new(challenge)) as *mut c_void
             }
-            _ => panic!("Can't load mCaptcha from old redis RDB, encver {}", encver),
+            Err(e) => quarantine::on_load_error(
+                rdb,
+                &MCAPTCHA_CHALLENGE_TYPE,
+                "challenge",
+                encver,
+                e,
+                || Challenge::new(0, 0),
+            ),
         };
+        if !value.is_null() {
+            metrics::CHALLENGES.incr();
+        }
+        value
+    }
 
-        Box::into_raw(Box::new(challenge)) as *mut c_void
+    fn load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> CacheResult<Challenge> {
+        match encver {
+            0 => {
+                let challenge: AddVisitorResult =
+                    Format::Json.parse_slice(raw::load_string(rdb)?.as_slice())?;
+                Ok(Challenge(challenge))
+            }
+            1 => load_fields(rdb),
+            _ => Err(CacheError::new(format!(
+                "Can't load challenge from unknown encver: {}",
+                encver
+            ))),
+        }
     }
 
     pub unsafe extern "C" fn free(value: *mut c_void) {
         let val = value as *mut Challenge;
-        Box::from_raw(val);
+        drop(Box::from_raw(val));
+        metrics::CHALLENGES.decr();
+    }
+
+    pub unsafe extern "C" fn mem_usage(_value: *const c_void) -> usize {
+        size_of::<Challenge>()
+    }
+
+    pub unsafe extern "C" fn digest(md: *mut raw::RedisModuleDigest, value: *mut c_void) {
+        let challenge = &*(value as *mut Challenge);
+        digest_add_u64(md, challenge.0.duration);
+        digest_add_u64(md, challenge.0.difficulty_factor as u64);
+        digest_end_sequence(md);
+    }
+
+    pub unsafe extern "C" fn copy(
+        _fromkey: *mut raw::RedisModuleString,
+        tokey: *mut raw::RedisModuleString,
+        value: *const c_void,
+    ) -> *mut c_void {
+        let challenge = &*(value as *const Challenge);
+        let key = String::from_utf8_lossy(RedisString::string_as_slice(tokey));
+        Challenge::index_key(&key, challenge);
+        metrics::CHALLENGES.incr();
+        Box::into_raw(Box::new(challenge.clone())) as *mut c_void
     }
 
     #[allow(non_snake_case, unused)]
     pub unsafe extern "C" fn rdb_save(rdb: *mut raw::RedisModuleIO, value: *mut c_void) {
+        let challenge = &*(value as *mut Challenge);
+        raw::save_unsigned(rdb, challenge.0.duration);
+        raw::save_unsigned(rdb, challenge.0.difficulty_factor as u64);
+    }
+
+    /// load challenge saved by [rdb_save]
+    fn load_fields(rdb: *mut raw::RedisModuleIO) -> CacheResult<Challenge> {
+        let duration = raw::load_unsigned(rdb)?;
+        let difficulty = raw::load_unsigned(rdb)? as u32;
+        Ok(Challenge::new(duration, difficulty))
+    }
+
+    pub unsafe extern "C" fn aof_rewrite(
+        aof: *mut raw::RedisModuleIO,
+        key: *mut raw::RedisModuleString,
+        value: *mut c_void,
+    ) {
         let challenge = &*(value as *mut Challenge);
         match &serde_json::to_string(&challenge.0) {
-            Ok(string) => raw::save_string(rdb, string),
-            Err(e) => panic!("error while rdb_save: {}", e),
+            Ok(string) => emit_aof(aof, "MCAPTCHA_CACHE.RESTORE_CHALLENGE", key, string),
+            Err(e) => panic!("error while aof_rewrite: {}", e),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use mcaptcha_pow_sha256::ConfigBuilder;
+
+    use super::*;
+
+    const SALT: &str = "79ziepia7vhjgviiwjhnend3ofjqocsi2winc4ptqhmkvcajihywxcizewvckg9h6gs4j83v9";
+
+    #[test]
+    fn check_pow_works() {
+        let config = ConfigBuilder::default().salt(SALT.into()).build().unwrap();
+        let phrase = "challenge".to_owned();
+        let pow = config.prove_work(&phrase, 500).unwrap();
+
+        let challenge = Challenge::new(5, 500);
+        assert_eq!(challenge.check_pow(SALT, &phrase, &pow), Verdict::Valid);
+        assert_eq!(
+            challenge.check_pow("other salt", &phrase, &pow),
+            Verdict::InvalidProof
+        );
+        assert_eq!(
+            challenge.check_pow(SALT, "other challenge", &pow),
+            Verdict::InvalidProof
+        );
+        assert_eq!(
+            Challenge::new(5, 0).check_pow(SALT, &phrase, &pow),
+            Verdict::Valid
+        );
+
+        // work that is only sufficient for a lower difficulty factor
+        let weak = (1..)
+            .map(|nonce| {
+                let pow = PoWBuilder::default()
+                    .nonce(nonce)
+                    .result(String::new())
+                    .build()
+                    .unwrap();
+                let result = config.calculate(&pow, &phrase).unwrap();
+                PoWBuilder::default()
+                    .nonce(nonce)
+                    .result(result.to_string())
+                    .build()
+                    .unwrap()
+            })
+            .find(|pow| !config.is_sufficient_difficulty(pow, 500_000))
+            .unwrap();
+        assert!(config.is_valid_proof(&weak, &phrase));
+        assert_eq!(
+            Challenge::new(5, 500_000).check_pow(SALT, &phrase, &weak),
+            Verdict::InsufficientWork
+        );
+    }
+}
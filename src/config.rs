@@ -0,0 +1,316 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Module configuration, registered with the Redis module config API. Parameters are passed as
+//! name value pairs when module is loaded:
+//!
+//! ```bash
+//! redis-server --loadmodule libcache.so bucket-expiry-offset 60 key-prefix tenantA
+//! ```
+//!
+//! and, unless they are immutable, can be changed at runtime with
+//! `CONFIG SET mcaptcha_cache.<name> <value>` and persisted with `CONFIG REWRITE`.
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use redis_module::logging::RedisLogLevel;
+use redis_module::{
+    Context, EnumConfigurationValue, NextArg, RedisError, RedisResult, RedisString, RedisValue,
+    Status,
+};
+
+use crate::errors::*;
+use crate::quarantine::LoadPolicy;
+
+/// initial allocation amount of bucket[crate::bucket::Bucket]
+pub static HIT_PER_SECOND: AtomicI64 = AtomicI64::new(100);
+/// If buckets perform clean up at x instant, then buckets themselves will get cleaned
+/// up at x + bucket_expiry_offset seconds(if they haven't already been cleaned up)
+pub static BUCKET_EXPIRY_OFFSET: AtomicI64 = AtomicI64::new(30);
+/// bucket instants are rounded up to slots of this many seconds. 0 disables rounding
+pub static BUCKET_RESOLUTION: AtomicI64 = AtomicI64::new(0);
+/// safeties expire after this many multiples of their mCaptcha's duration, if not boosted
+pub static SAFETY_EXPIRY_MULTIPLIER: AtomicI64 = AtomicI64::new(2);
+
+lazy_static! {
+    /// prefix of captcha, bucket and challenge keys
+    pub static ref KEY_PREFIX: Mutex<String> = Mutex::new(crate::PKG_NAME.into());
+    /// prefix of mCaptcha safety keys
+    pub static ref SAFETY_PREFIX: Mutex<String> = Mutex::new("safety:".into());
+    /// what to do with values that can't be loaded from RDB
+    pub static ref RDB_LOAD_POLICY: Mutex<LoadPolicy> = Mutex::new(LoadPolicy::Fail);
+    /// messages below this level are not logged
+    pub static ref LOG_LEVEL: Mutex<LogLevel> = Mutex::new(LogLevel::Debug);
+    /// salt of proofs of work, must be the same as that of mCaptcha. PoW verification is
+    /// disabled when empty
+    pub static ref POW_SALT: Mutex<String> = Mutex::new(String::new());
+}
+
+/// names of configuration parameters, in the order they are listed by `CONFIG GET`. Secrets are
+/// accepted as module arguments but never listed
+pub const NAMES: [&str; 9] = [
+    "hit-per-second",
+    "bucket-expiry-offset",
+    "bucket-resolution",
+    "safety-expiry-multiplier",
+    "key-prefix",
+    "safety-prefix",
+    "rdb-load-policy",
+    "log-level",
+    "pow-salt",
+];
+
+#[inline]
+pub fn hit_per_second() -> usize {
+    HIT_PER_SECOND.load(Ordering::Relaxed) as usize
+}
+
+#[inline]
+pub fn bucket_expiry_offset() -> u64 {
+    BUCKET_EXPIRY_OFFSET.load(Ordering::Relaxed) as u64
+}
+
+#[inline]
+pub fn bucket_resolution() -> u64 {
+    BUCKET_RESOLUTION.load(Ordering::Relaxed) as u64
+}
+
+#[inline]
+pub fn safety_expiry_multiplier() -> u64 {
+    SAFETY_EXPIRY_MULTIPLIER.load(Ordering::Relaxed) as u64
+}
+
+#[inline]
+pub fn key_prefix() -> String {
+    KEY_PREFIX.lock().unwrap().clone()
+}
+
+#[inline]
+pub fn safety_prefix() -> String {
+    SAFETY_PREFIX.lock().unwrap().clone()
+}
+
+#[inline]
+pub fn rdb_load_policy() -> LoadPolicy {
+    *RDB_LOAD_POLICY.lock().unwrap()
+}
+
+#[inline]
+pub fn log_level() -> LogLevel {
+    *LOG_LEVEL.lock().unwrap()
+}
+
+#[inline]
+pub fn pow_salt() -> String {
+    POW_SALT.lock().unwrap().clone()
+}
+
+/// module log verbosity, from most to least verbose
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum LogLevel {
+    Debug,
+    Verbose,
+    Notice,
+    Warning,
+}
+
+const LOG_LEVELS: [(&str, LogLevel); 4] = [
+    ("debug", LogLevel::Debug),
+    ("verbose", LogLevel::Verbose),
+    ("notice", LogLevel::Notice),
+    ("warning", LogLevel::Warning),
+];
+
+impl TryFrom<i32> for LogLevel {
+    type Error = RedisError;
+
+    fn try_from(v: i32) -> Result<Self, Self::Error> {
+        LOG_LEVELS
+            .get(v as usize)
+            .map(|(_, level)| *level)
+            .ok_or(RedisError::Str("Unsupported log level"))
+    }
+}
+
+impl From<LogLevel> for i32 {
+    fn from(level: LogLevel) -> Self {
+        level as i32
+    }
+}
+
+impl EnumConfigurationValue for LogLevel {
+    fn get_options(&self) -> (Vec<String>, Vec<i32>) {
+        LOG_LEVELS
+            .iter()
+            .map(|(name, level)| (name.to_string(), i32::from(*level)))
+            .unzip()
+    }
+}
+
+impl LogLevel {
+    pub fn name(&self) -> &'static str {
+        LOG_LEVELS[*self as usize].0
+    }
+}
+
+impl From<LogLevel> for RedisLogLevel {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Debug => RedisLogLevel::Debug,
+            LogLevel::Verbose => RedisLogLevel::Verbose,
+            LogLevel::Notice => RedisLogLevel::Notice,
+            LogLevel::Warning => RedisLogLevel::Warning,
+        }
+    }
+}
+
+/// log `message` at `level`, unless `log-level` is less verbose
+pub fn log(ctx: &Context, level: LogLevel, message: &str) {
+    if level >= log_level() {
+        ctx.log(level.into(), message);
+    }
+}
+
+#[inline]
+pub fn log_debug(ctx: &Context, message: &str) {
+    log(ctx, LogLevel::Debug, message)
+}
+
+#[inline]
+pub fn log_warning(ctx: &Context, message: &str) {
+    log(ctx, LogLevel::Warning, message)
+}
+
+/// get value of parameter `name`. `pow-salt` is a secret and isn't readable
+fn get(name: &str) -> Option<String> {
+    let value = match name {
+        "hit-per-second" => hit_per_second().to_string(),
+        "bucket-expiry-offset" => bucket_expiry_offset().to_string(),
+        "bucket-resolution" => bucket_resolution().to_string(),
+        "safety-expiry-multiplier" => safety_expiry_multiplier().to_string(),
+        "key-prefix" => key_prefix(),
+        "safety-prefix" => safety_prefix(),
+        "rdb-load-policy" => rdb_load_policy().name().into(),
+        "log-level" => log_level().name().into(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// check module arguments, passed as name value pairs. Values are parsed and range checked by
+/// Redis when they are registered, this rejects what Redis ignores: unknown names and missing
+/// values
+fn check_args<'a, I: Iterator<Item = &'a str>>(mut args: I) -> CacheResult<()> {
+    while let Some(name) = args.next() {
+        if !NAMES.contains(&name) {
+            return Err(CacheError::new(format!(
+                "Unknown configuration parameter: {}",
+                name
+            )));
+        }
+        if args.next().is_none() {
+            return Err(CacheError::new(format!(
+                "Missing value for configuration parameter: {}",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn check_prefix(name: &str, value: &str) -> CacheResult<()> {
+    if value.is_empty() || value.contains(|c: char| c == '{' || c == '}' || c.is_whitespace()) {
+        Err(CacheError::new(format!(
+            "Invalid value for {}: {:?}, expected a non-empty prefix without braces or whitespace",
+            name, value
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// check module arguments and immutable parameters. Runs when module is loaded, after
+/// configuration is registered and before any key is accessed
+pub fn init(ctx: &Context, args: &[RedisString]) -> Status {
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string_lossy()).collect();
+    let res = check_args(args.iter().map(String::as_str))
+        .and_then(|_| check_prefix("key-prefix", &key_prefix()))
+        .and_then(|_| check_prefix("safety-prefix", &safety_prefix()));
+    match res {
+        Ok(()) => Status::Ok,
+        Err(e) => {
+            ctx.log_warning(&format!("Can't load module: {}", e));
+            Status::Err
+        }
+    }
+}
+
+/// `MCAPTCHA_CACHE.CONFIG GET <name|*>`: list configuration parameters as name value pairs
+pub fn config(_ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    let mut args = args.into_iter().skip(1);
+    let subcommand = args.next_string()?;
+    if !subcommand.eq_ignore_ascii_case("GET") {
+        return Err(CacheError::new(format!("Unknown subcommand: {}", subcommand)).into());
+    }
+    let pattern = args.next_string()?;
+    args.done()?;
+
+    let mut res = Vec::new();
+    for name in NAMES
+        .iter()
+        .filter(|name| pattern == "*" || pattern == **name)
+    {
+        if let Some(value) = get(name) {
+            res.push(RedisValue::BulkString((*name).into()));
+            res.push(RedisValue::BulkString(value));
+        }
+    }
+    Ok(RedisValue::Array(res))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_check_works() {
+        assert!(check_args(
+            ["bucket-expiry-offset", "60", "key-prefix", "tenantA"]
+                .iter()
+                .copied(),
+        )
+        .is_ok());
+        assert!(check_prefix("key-prefix", "tenantA").is_ok());
+
+        let err = [vec!["bucket-expiry-offset"], vec!["unknown", "1"]];
+        for args in err.iter() {
+            assert!(check_args(args.iter().copied()).is_err(), "{:?}", args);
+        }
+        for prefix in ["", "{tenant}", "ten ant"].iter() {
+            assert!(check_prefix("key-prefix", prefix).is_err(), "{:?}", prefix);
+        }
+
+        for (name, level) in LOG_LEVELS.iter() {
+            assert_eq!(LogLevel::try_from(i32::from(*level)).unwrap(), *level);
+            assert_eq!(level.name(), *name);
+        }
+        assert!(LogLevel::Debug < LogLevel::Warning);
+
+        assert!(check_args(["pow-salt", "secret"].iter().copied()).is_ok());
+        assert!(get("pow-salt").is_none());
+    }
+}
@@ -30,10 +30,14 @@ pub enum CacheError {
     RedisError(redis_module::RedisError),
     #[display(fmt = "Captcha not found")]
     CaptchaNotFound,
+    #[display(fmt = "Captcha already exists")]
+    DuplicateCaptcha,
     #[display(fmt = "Challenge not found")]
     ChallengeNotFound,
     #[display(fmt = "Challenge already exists")]
     DuplicateChallenge,
+    #[display(fmt = "Token already exists")]
+    DuplicateToken,
 }
 
 impl CacheError {
@@ -60,6 +64,42 @@ impl From<serde_json::Error> for CacheError {
     }
 }
 
+impl From<bincode::Error> for CacheError {
+    fn from(e: bincode::Error) -> Self {
+        CacheError::Msg(e.to_string())
+    }
+}
+
+impl From<rmp_serde::decode::Error> for CacheError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        CacheError::Msg(e.to_string())
+    }
+}
+
+impl From<rmp_serde::encode::Error> for CacheError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        CacheError::Msg(e.to_string())
+    }
+}
+
+impl From<ciborium::de::Error<std::io::Error>> for CacheError {
+    fn from(e: ciborium::de::Error<std::io::Error>) -> Self {
+        CacheError::Msg(e.to_string())
+    }
+}
+
+impl From<ciborium::ser::Error<std::io::Error>> for CacheError {
+    fn from(e: ciborium::ser::Error<std::io::Error>) -> Self {
+        CacheError::Msg(e.to_string())
+    }
+}
+
+impl From<redis_module::error::Error> for CacheError {
+    fn from(e: redis_module::error::Error) -> Self {
+        CacheError::Msg(e.to_string())
+    }
+}
+
 impl From<RedisError> for CacheError {
     fn from(e: redis_module::RedisError) -> Self {
         CacheError::RedisError(e)
@@ -91,8 +131,10 @@ impl From<CacheError> for RedisError {
             CacheError::Msg(val) => RedisError::String(val),
             CacheError::RedisError(val) => val,
             CacheError::CaptchaNotFound => RedisError::String(format!("{}", e)),
+            CacheError::DuplicateCaptcha => RedisError::String(format!("{}", e)),
             CacheError::ChallengeNotFound => RedisError::String(format!("{}", e)),
             CacheError::DuplicateChallenge => RedisError::String(format!("{}", e)),
+            CacheError::DuplicateToken => RedisError::String(format!("{}", e)),
         }
     }
 }
@@ -17,28 +17,31 @@
 use lazy_static::lazy_static;
 //use redis_module::{NotifyEvent, key};
 //use redis_module::{redis_command, redis_event_handler, redis_module};
-use redis_module::{
-    key, redis_command, redis_event_handler, redis_module, Context, NextArg, NotifyEvent,
-    RedisError, RedisResult, RedisString, RedisValue, Status,
-};
+use redis_module::server_events::{LoadingSubevent, LOADING_SERVER_EVENTS_LIST};
+use redis_module::{redis_module, Context, NextArg, NotifyEvent, RedisResult};
 //use redis_module::{NextArg, RedisResult};
 //use redis_module::RedisError;
 //use redis_module::Context;
 
 mod bucket;
 mod challenge;
+mod config;
 mod errors;
 mod mcaptcha;
+mod metrics;
+mod quarantine;
 mod safety;
+mod sliding_window;
+mod token;
 mod utils;
 
 use bucket::MCAPTCHA_BUCKET_TYPE;
 use challenge::MCAPTCHA_CHALLENGE_TYPE;
+use config::log_debug;
 use mcaptcha::MCAPTCHA_MCAPTCHA_TYPE;
 use safety::MCAPTCHA_SAFETY_TYPE;
+use token::MCAPTCHA_TOKEN_TYPE;
 
-/// Initial allocation amount of bucket[bucket::Bucket]
-pub const HIT_PER_SECOND: usize = 100;
 pub const PKG_NAME: &str = "mcap";
 pub const PKG_VERSION: usize = 0;
 
@@ -49,10 +52,6 @@ pub const PKG_VERSION: usize = 0;
 // so, I guess it's okay for us to just use timer and not enfore pinning
 // and PKG_NAME
 pub const PREFIX_BUCKET_TIMER: &str = "timer:";
-pub const PREFIX_SAFETY: &str = "safety:";
-/// If buckets perform clean up at x instant, then buckets themselves will get cleaned
-/// up at x + BUCKET_EXPIRY_OFFSET(if they haven't already been cleaned up)
-pub const BUCKET_EXPIRY_OFFSET: u64 = 30;
 
 lazy_static! {
     /// node unique identifier, useful when running in cluster mode
@@ -61,11 +60,19 @@ lazy_static! {
         let mut rng = rand::thread_rng();
         rng.gen()
     };
+    // Key prefixes are configurable. They are evaluated on first use, which is after module
+    // configuration is loaded
     /// counter/captcha key prefix
-    pub static ref PREFIX_CAPTCHA: String = format!("{}:captcha::", PKG_NAME);
+    pub static ref PREFIX_CAPTCHA: String = format!("{}:captcha::", config::key_prefix());
     /// bucket key prefix
-    pub static ref PREFIX_BUCKET: String = format!("{}:bucket:{{{}}}:", PKG_NAME, *ID);
-    pub static ref PREFIX_CHALLENGE: String = format!("{}:CHALLENGE", PKG_NAME);
+    pub static ref PREFIX_BUCKET: String = format!("{}:bucket:{{{}}}:", config::key_prefix(), *ID);
+    /// bucket key prefix, regardless of the node that created the bucket
+    pub static ref PREFIX_BUCKET_ANY_NODE: String = format!("{}:bucket:", config::key_prefix());
+    pub static ref PREFIX_CHALLENGE: String = format!("{}:CHALLENGE", config::key_prefix());
+    /// verification token key prefix
+    pub static ref PREFIX_TOKEN: String = format!("{}:TOKEN", config::key_prefix());
+    /// mCaptcha safety key prefix
+    pub static ref PREFIX_SAFETY: String = config::safety_prefix();
 }
 
 pub fn on_delete(ctx: &Context, event_type: NotifyEvent, event: &str, key_name: &[u8]) {
@@ -75,7 +82,7 @@ pub fn on_delete(ctx: &Context, event_type: NotifyEvent, event: &str, key_name:
         "Received event: {:?} on key: {} via event: {}",
         event_type, key_name, event
     );
-    ctx.log_debug(msg.as_str());
+    log_debug(ctx, msg.as_str());
 
     if utils::is_bucket_timer(&key_name) {
         bucket::Bucket::on_delete(ctx, event_type, event, &key_name);
@@ -84,28 +91,106 @@ pub fn on_delete(ctx: &Context, event_type: NotifyEvent, event: &str, key_name:
     }
 }
 
+/// copies of mcaptchas need their own safety
+pub fn on_copy(ctx: &Context, _event_type: NotifyEvent, event: &str, key_name: &[u8]) {
+    if event != "copy_to" {
+        return;
+    }
+    let key_name = redis_module::RedisString::create_from_slice(ctx.ctx, key_name);
+    let key_name = key_name.to_string();
+    if utils::is_captcha_key(&key_name) {
+        log_debug(
+            ctx,
+            &format!("Creating safety for copied mcaptcha: {}", key_name),
+        );
+        safety::MCaptchaSafety::protect(ctx, &key_name);
+    }
+}
+
+/// once loading is complete, placeholders of values that couldn't be loaded are removed and
+/// timers of loaded buckets are re-armed
+#[linkme::distributed_slice(LOADING_SERVER_EVENTS_LIST)]
+fn on_loading(ctx: &Context, event: LoadingSubevent) {
+    if event == LoadingSubevent::Ended {
+        quarantine::purge(ctx);
+        bucket::Bucket::rearm_timers(ctx);
+    }
+}
+
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub mod redis {
     use super::*;
 
+    use redis_module::configuration::ConfigurationFlags;
+
+    use crate::config::init as config_init;
+
+    // Redis allocator is only available when module is loaded by Redis, unit tests use the
+    // system allocator
+    #[cfg(not(test))]
+    type Allocator = redis_module::alloc::RedisAlloc;
+    #[cfg(not(test))]
+    const ALLOCATOR: Allocator = redis_module::alloc::RedisAlloc;
+    #[cfg(test)]
+    type Allocator = std::alloc::System;
+    #[cfg(test)]
+    const ALLOCATOR: Allocator = std::alloc::System;
+
     redis_module! {
         name: "mcaptcha_cache",
         version: PKG_VERSION,
-        allocator: (redis_module::alloc::RedisAlloc, redis_module::alloc::RedisAlloc),
-        data_types: [MCAPTCHA_BUCKET_TYPE, MCAPTCHA_MCAPTCHA_TYPE, MCAPTCHA_SAFETY_TYPE, MCAPTCHA_CHALLENGE_TYPE],
+        allocator: (Allocator, ALLOCATOR),
+        data_types: [MCAPTCHA_BUCKET_TYPE, MCAPTCHA_MCAPTCHA_TYPE, MCAPTCHA_SAFETY_TYPE, MCAPTCHA_CHALLENGE_TYPE, MCAPTCHA_TOKEN_TYPE],
+        init: config_init,
         commands: [
             ["MCAPTCHA_CACHE.ADD_VISITOR", bucket::Bucket::counter_create, "write", 1, 1, 1],
+            ["MCAPTCHA_CACHE.ADD_VISITORS", bucket::Bucket::add_visitors, "write", 1, 1, 1],
+            ["MCAPTCHA_CACHE.SCHEDULED_DECREMENTS", bucket::Bucket::scheduled_decrements, "readonly", 1, 1, 1],
             ["MCAPTCHA_CACHE.GET", mcaptcha::MCaptcha::get_count, "readonly", 1, 1, 1],
-            ["MCAPTCHA_CACHE.ADD_CAPTCHA", mcaptcha::MCaptcha::add_captcha, "readonly", 1, 1, 1],
+            ["MCAPTCHA_CACHE.GET_STATE", mcaptcha::MCaptcha::get_state, "readonly", 1, 1, 1],
+            ["MCAPTCHA_CACHE.ADD_CAPTCHA", mcaptcha::MCaptcha::add_captcha, "write", 1, 1, 1],
+            ["MCAPTCHA_CACHE.UPDATE_CAPTCHA", mcaptcha::MCaptcha::update_captcha, "write", 1, 1, 1],
             ["MCAPTCHA_CACHE.DELETE_CAPTCHA", mcaptcha::MCaptcha::delete_captcha, "write", 1, 1, 1],
-            ["MCAPTCHA_CACHE.RENAME_CAPTCHA", mcaptcha::MCaptcha::rename, "write", 1, 1, 1],
+            ["MCAPTCHA_CACHE.RENAME_CAPTCHA", mcaptcha::MCaptcha::rename, "write", 1, 2, 1],
             ["MCAPTCHA_CACHE.CAPTCHA_EXISTS", mcaptcha::MCaptcha::captcha_exists, "readonly", 1, 1, 1],
+            ["MCAPTCHA_CACHE.STATS", mcaptcha::MCaptcha::stats, "readonly", 1, 1, 1],
             ["MCAPTCHA_CACHE.ADD_CHALLENGE", challenge::Challenge::create_challenge, "write", 1, 1, 1],
             ["MCAPTCHA_CACHE.GET_CHALLENGE", challenge::Challenge::get_challenge, "write", 1, 1, 1],
+            ["MCAPTCHA_CACHE.VERIFY_CHALLENGE", challenge::Challenge::verify_challenge, "write", 1, 1, 1],
+            ["MCAPTCHA_CACHE.VERIFY_POW", challenge::Challenge::verify_pow, "write", 1, 1, 1],
             ["MCAPTCHA_CACHE.DELETE_CHALLENGE", challenge::Challenge::delete_challenge, "write", 1, 1, 1],
+            ["MCAPTCHA_CACHE.ADD_TOKEN", token::Token::add_token, "write", 1, 1, 1],
+            ["MCAPTCHA_CACHE.VALIDATE_TOKEN", token::Token::validate_token, "write", 1, 1, 1],
+            ["MCAPTCHA_CACHE.RESTORE_BUCKET", bucket::Bucket::restore, "write", 1, 1, 1],
+            ["MCAPTCHA_CACHE.RESTORE_CAPTCHA", mcaptcha::MCaptcha::restore, "write", 1, 1, 1],
+            ["MCAPTCHA_CACHE.RESTORE_SAFETY", safety::MCaptchaSafety::restore, "write", 1, 1, 1],
+            ["MCAPTCHA_CACHE.RESTORE_CHALLENGE", challenge::Challenge::restore, "write", 1, 1, 1],
+            ["MCAPTCHA_CACHE.RESTORE_TOKEN", token::Token::restore, "write", 1, 1, 1],
+            ["MCAPTCHA_CACHE.QUARANTINE", quarantine::quarantine, "readonly", 0, 0, 0],
+            ["MCAPTCHA_CACHE.CONFIG", config::config, "readonly", 0, 0, 0],
         ],
        event_handlers: [
             [@EXPIRED @EVICTED: on_delete],
+            [@GENERIC: on_copy],
+        ],
+        configurations: [
+            i64: [
+                ["hit-per-second", &config::HIT_PER_SECOND, 100, 1, 1_000_000, ConfigurationFlags::DEFAULT, None],
+                ["bucket-expiry-offset", &config::BUCKET_EXPIRY_OFFSET, 30, 1, 1_000_000, ConfigurationFlags::DEFAULT, None],
+                ["bucket-resolution", &config::BUCKET_RESOLUTION, 0, 0, 86_400, ConfigurationFlags::DEFAULT, None],
+                ["safety-expiry-multiplier", &config::SAFETY_EXPIRY_MULTIPLIER, 2, 1, 1_000, ConfigurationFlags::DEFAULT, None],
+            ],
+            string: [
+                ["key-prefix", &*config::KEY_PREFIX, PKG_NAME, ConfigurationFlags::IMMUTABLE, None],
+                ["safety-prefix", &*config::SAFETY_PREFIX, "safety:", ConfigurationFlags::IMMUTABLE, None],
+                ["pow-salt", &*config::POW_SALT, "", ConfigurationFlags::SENSITIVE, None],
+            ],
+            bool: [],
+            enum: [
+                ["rdb-load-policy", &*config::RDB_LOAD_POLICY, quarantine::LoadPolicy::Fail, ConfigurationFlags::DEFAULT, None],
+                ["log-level", &*config::LOG_LEVEL, config::LogLevel::Debug, ConfigurationFlags::DEFAULT, None],
+            ],
+            module_args_as_configuration: true,
         ]
     }
 }
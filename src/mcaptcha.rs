@@ -14,11 +14,16 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
-use libmcaptcha::dev::{AddVisitorResult, CreateMCaptcha, DefenseBuilder, MCaptchaBuilder};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+
+use libmcaptcha::defense::Level;
+use libmcaptcha::dev::{AddVisitorResult, DefenseBuilder, MCaptchaBuilder};
 use redis_module::key::RedisKey;
 use redis_module::key::RedisKeyWritable;
 use redis_module::native_types::RedisType;
 use redis_module::raw::KeyType;
+use redis_module::redisvalue::RedisValueKey;
 use redis_module::RedisError;
 use redis_module::RedisString;
 use redis_module::RedisValue;
@@ -29,16 +34,103 @@ use redis_module::raw;
 
 use serde::{Deserialize, Serialize};
 
-use crate::bucket::Format;
+use crate::bucket::{Bucket, Format};
+use crate::challenge::Challenge;
+use crate::config::log_debug;
 use crate::errors::*;
+use crate::metrics;
+use crate::quarantine;
 use crate::safety::MCaptchaSafety;
+use crate::sliding_window::SlidingWindow;
 use crate::utils::*;
 
-const REDIS_MCPATCHA_MCAPTCHA_TYPE_VERSION: i32 = 0;
+/// mCaptcha type version, aka encoding version
+///
+/// - 0: JSON
+/// - 1: binary
+/// - 2: binary, followed by window in milliseconds
+/// - 3: binary, followed by window in milliseconds and sliding window
+/// - 4: binary, followed by window in milliseconds, sliding window and statistics
+const REDIS_MCPATCHA_MCAPTCHA_TYPE_VERSION: i32 = 4;
+
+/// how visitors of an mCaptcha are counted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    /// visitors are decremented by buckets, scheduled to run at the end of their window
+    #[default]
+    LeakyBucket,
+    /// visitors are counted in a [SlidingWindow] and expire lazily
+    SlidingWindow,
+}
+
+/// mCaptcha configuration, accepted by `ADD_CAPTCHA`. Unlike libmcaptcha's `CreateMCaptcha`,
+/// duration, in seconds, can be fractional
+#[derive(Deserialize)]
+pub struct CreateMCaptcha {
+    pub levels: Vec<Level>,
+    pub duration: f64,
+    #[serde(default)]
+    pub mode: Mode,
+}
+
+/// new configuration of an mCaptcha, accepted by `UPDATE_CAPTCHA`. Duration, in seconds, is kept
+/// when it isn't set
+#[derive(Deserialize)]
+pub struct UpdateMCaptcha {
+    pub levels: Vec<Level>,
+    #[serde(default)]
+    pub duration: Option<f64>,
+}
+
+/// statistics of an mCaptcha, since it was created
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    /// highest visitor count seen
+    pub peak_visitors: u32,
+    /// visitors added
+    pub total_visitors: u64,
+    /// challenges added
+    pub challenges_issued: u64,
+    /// challenges retrieved for verification
+    pub challenges_verified: u64,
+    /// time of last visit, milliseconds from UNIX_EPOCH. 0 if mCaptcha hasn't been visited
+    pub last_visit: u64,
+}
 
-#[derive(Serialize, Deserialize)]
+/// response of `STATS`
+#[derive(Serialize)]
+struct StatsResult {
+    visitors: u32,
+    difficulty_factor: u32,
+    /// window in seconds, can be fractional
+    duration: f64,
+    levels: Vec<Level>,
+    peak_visitors: u32,
+    total_visitors: u64,
+    challenges_issued: u64,
+    challenges_verified: u64,
+    /// milliseconds from UNIX_EPOCH, null if mCaptcha hasn't been visited
+    last_visit: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct MCaptcha {
     m: libmcaptcha::dev::MCaptcha,
+    /// window in milliseconds. Only set when window isn't a whole number of seconds, libmcaptcha
+    /// then holds window rounded up to seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+    /// only set for mCaptchas in [Mode::SlidingWindow]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    window: Option<SlidingWindow>,
+    #[serde(default)]
+    stats: Stats,
+    /// instants, in milliseconds from UNIX_EPOCH, and names of buckets with pending decrements
+    /// of the mCaptcha. Buckets are persisted on their own, so these are rebuilt by
+    /// [Bucket::rearm_timers] once loading is complete
+    #[serde(skip)]
+    scheduled: BTreeSet<(u64, String)>,
 }
 
 impl MCaptcha {
@@ -55,12 +147,150 @@ impl MCaptcha {
         }
         let defense = defense_builder.build()?;
 
+        if !m.duration.is_finite() || m.duration <= 0.0 {
+            return Err(CacheError::new(format!("Invalid duration: {}", m.duration)));
+        }
+        let duration_ms = (m.duration * 1000.0).round() as u64;
+        if duration_ms == 0 {
+            return Err(CacheError::new(format!(
+                "Duration shorter than a millisecond: {}",
+                m.duration
+            )));
+        }
+
+        let window = match m.mode {
+            Mode::LeakyBucket => None,
+            Mode::SlidingWindow => Some(SlidingWindow::new(duration_ms)),
+        };
         let m = MCaptchaBuilder::default()
             .defense(defense)
-            .duration(m.duration)
+            .duration(duration_ms.div_ceil(1000))
             .build()?;
 
-        Ok(MCaptcha { m })
+        let duration_ms = if duration_ms.is_multiple_of(1000) {
+            None
+        } else {
+            Some(duration_ms)
+        };
+        Ok(MCaptcha {
+            m,
+            duration_ms,
+            window,
+            stats: Stats::default(),
+            scheduled: BTreeSet::new(),
+        })
+    }
+
+    /// replace defense levels and, optionally, duration. Visitor count, mode and statistics are
+    /// kept. Visitors are replayed so that difficulty matches the new levels
+    fn update(&mut self, payload: UpdateMCaptcha, now: u64) -> CacheResult<()> {
+        let mode = if self.is_sliding_window() {
+            Mode::SlidingWindow
+        } else {
+            Mode::LeakyBucket
+        };
+        let mut updated = Self::new(CreateMCaptcha {
+            levels: payload.levels,
+            duration: payload
+                .duration
+                .unwrap_or(self.get_duration_ms() as f64 / 1000.0),
+            mode,
+        })?;
+
+        self.expire_visitors(now);
+        let visitors = self.get_visitors();
+        updated.add_visitors(visitors)?;
+
+        let same_window = updated.get_duration_ms() == self.get_duration_ms();
+        if let (Some(window), Some(new_window)) = (self.window.take(), updated.window.as_mut()) {
+            if same_window {
+                *new_window = window;
+            } else {
+                // slots don't line up, visitors expire a window from now
+                new_window.advance(now);
+                new_window.add(visitors);
+            }
+        }
+        updated.stats = std::mem::take(&mut self.stats);
+        updated.scheduled = std::mem::take(&mut self.scheduled);
+        *self = updated;
+        Ok(())
+    }
+
+    /// visitors of mCaptchas in [Mode::SlidingWindow] expire lazily and aren't decremented by
+    /// buckets
+    #[inline]
+    pub fn is_sliding_window(&self) -> bool {
+        self.window.is_some()
+    }
+
+    /// expire visitors of mCaptchas in [Mode::SlidingWindow] that have fallen out of the window
+    /// at `now`, milliseconds from UNIX_EPOCH
+    #[inline]
+    pub fn expire_visitors(&mut self, now: u64) {
+        if let Some(window) = self.window.as_mut() {
+            let expired = window.advance(now);
+            if expired > 0 {
+                self.m.decrement_visitor_by(expired);
+            }
+        }
+    }
+
+    /// mCaptcha as it is at `now`, milliseconds from UNIX_EPOCH. Visitors of mCaptchas in
+    /// [Mode::SlidingWindow] that have fallen out of the window are expired on a copy, the way
+    /// [MCaptcha::register_visitors] expires them
+    pub fn at(&self, now: u64) -> Cow<'_, Self> {
+        match &self.window {
+            Some(window) if window.expired(now) > 0 => {
+                let mut mcaptcha = self.clone();
+                mcaptcha.expire_visitors(now);
+                Cow::Owned(mcaptcha)
+            }
+            _ => Cow::Borrowed(self),
+        }
+    }
+
+    /// register `count` visitors at `now`, milliseconds from UNIX_EPOCH. Visitors of mCaptchas in
+    /// [Mode::LeakyBucket] must be scheduled for decrement with buckets
+    #[inline]
+    pub fn register_visitors(&mut self, now: u64, count: u32) -> CacheResult<()> {
+        self.expire_visitors(now);
+        self.add_visitors(count)?;
+        if let Some(window) = self.window.as_mut() {
+            window.add(count);
+        }
+        self.stats.peak_visitors = self.stats.peak_visitors.max(self.get_visitors());
+        self.stats.total_visitors += u64::from(count);
+        self.stats.last_visit = now;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn get_stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// record that a challenge was added
+    #[inline]
+    pub fn challenge_issued(&mut self) {
+        self.stats.challenges_issued += 1;
+    }
+
+    /// record that a challenge was retrieved for verification
+    #[inline]
+    pub fn challenge_verified(&mut self) {
+        self.stats.challenges_verified += 1;
+    }
+
+    /// get visitor count at `now`, milliseconds from UNIX_EPOCH, leaving out visitors that have
+    /// expired but haven't been cleared yet
+    #[inline]
+    pub fn get_visitors_at(&self, now: u64) -> u32 {
+        let visitors = self.get_visitors();
+        match &self.window {
+            Some(window) => visitors.saturating_sub(window.expired(now)),
+            None => visitors,
+        }
     }
 
     /// increments the visitor count by one
@@ -69,31 +299,102 @@ impl MCaptcha {
         self.m.add_visitor()
     }
 
+    /// increments the visitor count by `count`, one visitor at a time. libmcaptcha's counters are
+    /// private, so there is no way to skip ahead without stepping through its defense levels
+    pub fn add_visitors(&mut self, count: u32) -> CacheResult<()> {
+        if count > u32::MAX - self.get_visitors() {
+            return Err(CacheError::new(format!("Invalid visitor count: {}", count)));
+        }
+        for _ in 0..count {
+            self.add_visitor();
+        }
+        Ok(())
+    }
+
     /// get current difficulty factor
     #[inline]
-    #[allow(dead_code)]
     pub fn get_difficulty(&self) -> u32 {
         self.m.get_difficulty()
     }
 
-    /// get [MCaptcha]'s lifetime
+    /// get [MCaptcha]'s lifetime, in seconds. Fractional windows are rounded up
     #[inline]
     pub fn get_duration(&self) -> u64 {
         self.m.get_duration()
     }
 
+    /// get [MCaptcha]'s lifetime, in milliseconds
+    #[inline]
+    pub fn get_duration_ms(&self) -> u64 {
+        self.duration_ms
+            .unwrap_or_else(|| self.m.get_duration() * 1000)
+    }
+
     /// get [MCaptcha]'s current visitor_threshold
     #[inline]
     pub fn get_visitors(&self) -> u32 {
         self.m.get_visitors()
     }
 
+    /// get [MCaptcha]'s defense levels
+    #[inline]
+    pub fn get_levels(&self) -> Vec<Level> {
+        self.m.get_defense().into()
+    }
+
+    /// get index of current defense level and visitor count above which difficulty goes up.
+    /// There's no such visitor count at the highest level
+    pub fn get_level(&self) -> (usize, Option<u32>) {
+        let defense = self.m.get_defense();
+        let threshold = defense.current_level().visitor_threshold;
+        let levels: Vec<Level> = defense.into();
+        let index = levels
+            .iter()
+            .position(|level| level.visitor_threshold == threshold)
+            .unwrap_or_default();
+        let next_threshold = Some(threshold).filter(|_| index + 1 < levels.len());
+        (index, next_threshold)
+    }
+
     /// decrement [MCaptcha]'s current visitor_threshold by specified count
     #[inline]
     pub fn decrement_visitor_by(&mut self, count: u32) {
         self.m.decrement_visitor_by(count)
     }
 
+    /// record that bucket `bucket_name`, at `bucket_instant`, has pending decrements of
+    /// [MCaptcha]
+    #[inline]
+    pub fn schedule_decrement(&mut self, bucket_instant: u64, bucket_name: &str) {
+        self.scheduled
+            .insert((bucket_instant, bucket_name.to_owned()));
+    }
+
+    /// decrement visitors by `count`, registered with bucket `bucket_name` at `bucket_instant`
+    #[inline]
+    pub fn run_decrement(&mut self, bucket_instant: u64, bucket_name: &str, count: u32) {
+        self.scheduled
+            .remove(&(bucket_instant, bucket_name.to_owned()));
+        self.decrement_visitor_by(count);
+    }
+
+    /// buckets with pending decrements of [MCaptcha], as `(bucket instant, bucket name)` pairs
+    /// sorted by bucket instant
+    #[inline]
+    pub fn get_scheduled(&self) -> &BTreeSet<(u64, String)> {
+        &self.scheduled
+    }
+
+    /// instant, in milliseconds from UNIX_EPOCH, of next decrement. That is the oldest pending
+    /// bucket in [Mode::LeakyBucket] and the next slot to expire after `now` in
+    /// [Mode::SlidingWindow]
+    pub fn next_decrement(&self, now: u64) -> Option<u64> {
+        match &self.window {
+            Some(window) => window.next_expiry(now),
+            None => self.scheduled.first().map(|(instant, _)| *instant),
+        }
+    }
+
     /// get mcaptcha from redis key writable
     #[inline]
     pub fn get_mut_mcaptcha(key: &RedisKeyWritable) -> CacheResult<Option<&mut Self>> {
@@ -121,41 +422,150 @@ impl MCaptcha {
         }
 
         match Self::get_mcaptcha(&stored_captcha)? {
-            Some(val) => Ok(RedisValue::Integer(val.get_visitors().into())),
+            Some(val) => Ok(RedisValue::Integer(
+                val.get_visitors_at(now_millis()?).into(),
+            )),
             None => Err(CacheError::CaptchaNotFound.into()),
         }
     }
 
+    /// Get visitor count, difficulty factor, defense level index, visitor count above which
+    /// difficulty goes up and seconds until the oldest pending decrement. Missing values are null
+    pub fn get_state(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+        let mut args = args.into_iter().skip(1);
+        let key_name = get_captcha_key(&args.next_string()?);
+        args.done()?;
+
+        let key = ctx.open_key(&RedisString::create_from_slice(
+            ctx.ctx,
+            key_name.as_bytes(),
+        ));
+        let mcaptcha = match Self::get_mcaptcha(&key)? {
+            Some(mcaptcha) => mcaptcha,
+            None => return Err(CacheError::CaptchaNotFound.into()),
+        };
+
+        let now = now_millis()?;
+        let mcaptcha = mcaptcha.at(now);
+        let next_decrement = mcaptcha.next_decrement(now);
+        let (level, next_threshold) = mcaptcha.get_level();
+
+        let optional =
+            |val: Option<u64>| val.map_or(RedisValue::Null, |v| RedisValue::Integer(v as i64));
+        let state: BTreeMap<RedisValueKey, RedisValue> = [
+            (
+                "visitors",
+                RedisValue::Integer(mcaptcha.get_visitors().into()),
+            ),
+            (
+                "difficulty_factor",
+                RedisValue::Integer(mcaptcha.get_difficulty().into()),
+            ),
+            ("level", RedisValue::Integer(level as i64)),
+            ("next_threshold", optional(next_threshold.map(u64::from))),
+            (
+                "seconds_until_decrement",
+                // rounded up, so that it is 0 only when decrement is overdue
+                optional(next_decrement.map(|instant| instant.saturating_sub(now).div_ceil(1000))),
+            ),
+        ]
+        .into_iter()
+        .map(|(name, val)| (RedisValueKey::String(name.into()), val))
+        .collect();
+        Ok(RedisValue::OrderedMap(state))
+    }
+
+    /// Get statistics of mCaptcha
+    pub fn stats(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+        let mut args = args.into_iter().skip(1);
+        let key_name = get_captcha_key(&args.next_string()?);
+        let format = Format::from_args(&mut args)?;
+
+        let key = ctx.open_key(&RedisString::create_from_slice(
+            ctx.ctx,
+            key_name.as_bytes(),
+        ));
+        let mcaptcha = match Self::get_mcaptcha(&key)? {
+            Some(mcaptcha) => mcaptcha,
+            None => return Err(CacheError::CaptchaNotFound.into()),
+        };
+
+        let mcaptcha = mcaptcha.at(now_millis()?);
+        let stats = mcaptcha.get_stats();
+        let res = StatsResult {
+            visitors: mcaptcha.get_visitors(),
+            difficulty_factor: mcaptcha.get_difficulty(),
+            duration: mcaptcha.get_duration_ms() as f64 / 1000.0,
+            levels: mcaptcha.get_levels(),
+            peak_visitors: stats.peak_visitors,
+            total_visitors: stats.total_visitors,
+            challenges_issued: stats.challenges_issued,
+            challenges_verified: stats.challenges_verified,
+            last_visit: Some(stats.last_visit).filter(|t| *t != 0),
+        };
+        Ok(format.to_vec(&res)?.into())
+    }
+
     /// Add captcha to redis
     pub fn add_captcha(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
         let mut args = args.into_iter().skip(1);
         let key_name = get_captcha_key(&args.next_string()?);
-        let json = args.next_string()?;
-        let mcaptcha: CreateMCaptcha = Format::Json.from_str(&json)?;
+        let payload = args.next_arg()?;
+        let format = Format::from_args(&mut args)?;
+        let mcaptcha: CreateMCaptcha = format.parse_slice(payload.as_slice())?;
         let mcaptcha = Self::new(mcaptcha)?;
 
-        Self::add_captcha_runner(ctx, &key_name, mcaptcha)
+        Self::add_captcha_runner(ctx, &key_name, mcaptcha)?;
+        ctx.replicate_verbatim();
+        REDIS_OK
     }
 
     #[inline]
     fn add_captcha_runner(ctx: &Context, key_name: &str, mcaptcha: MCaptcha) -> RedisResult {
         let duration = mcaptcha.get_duration();
+        let needs_safety = !mcaptcha.is_sliding_window();
         let key = ctx.open_key_writable(&RedisString::create_from_slice(
             ctx.ctx,
             key_name.as_bytes(),
         ));
         if key.key_type() == KeyType::Empty {
             key.set_value(&MCAPTCHA_MCAPTCHA_TYPE, mcaptcha)?;
-            ctx.log_debug(&format!("mcaptcha {} created", key_name));
-            MCaptchaSafety::new(ctx, duration, key_name)?;
+            metrics::MCAPTCHAS.incr();
+            log_debug(ctx, &format!("mcaptcha {} created", key_name));
+            if needs_safety {
+                MCaptchaSafety::new(ctx, duration, key_name)?;
+            }
             REDIS_OK
         } else {
             let msg = format!("mcaptcha {} exists", key_name);
-            ctx.log_debug(&msg);
+            log_debug(ctx, &msg);
             Err(CacheError::new(msg).into())
         }
     }
 
+    /// Update defense levels and, optionally, duration of mCaptcha in place. Visitor count,
+    /// statistics, safety and pending decrements are kept
+    pub fn update_captcha(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+        let mut args = args.into_iter().skip(1);
+        let key_name = get_captcha_key(&args.next_string()?);
+        let payload = args.next_arg()?;
+        let format = Format::from_args(&mut args)?;
+        let payload: UpdateMCaptcha = format.parse_slice(payload.as_slice())?;
+
+        let key = ctx.open_key_writable(&RedisString::create_from_slice(
+            ctx.ctx,
+            key_name.as_bytes(),
+        ));
+        match Self::get_mut_mcaptcha(&key)? {
+            Some(mcaptcha) => {
+                mcaptcha.update(payload, now_millis()?)?;
+                ctx.replicate_verbatim();
+                REDIS_OK
+            }
+            None => Err(CacheError::CaptchaNotFound.into()),
+        }
+    }
+
     /// check if captcha exists
     pub fn captcha_exists(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
         let mut args = args.into_iter().skip(1);
@@ -177,53 +587,148 @@ impl MCaptcha {
         !(key.key_type() == KeyType::Empty)
     }
 
-    /// implements mCaptcha rename: clones configuration from old name to new name and
-    /// deletes oldname
+    /// implements mCaptcha rename: moves mCaptcha, with its visitor count and statistics, to new
+    /// name along with its pending decrements and safety
     pub fn rename(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
         let mut args = args.into_iter().skip(1);
         let key_name = get_captcha_key(&args.next_string()?);
         let new_name = get_captcha_key(&args.next_string()?);
+        args.done()?;
 
-        let key = ctx.open_key(&RedisString::create_from_slice(
+        let key = ctx.open_key_writable(&RedisString::create_from_slice(
             ctx.ctx,
             key_name.as_bytes(),
         ));
-        if Self::captcha_exists_runner(&key) {
-            if let Some(mcaptcha) = Self::get_mcaptcha(&key)? {
-                let mcaptcha = MCaptcha {
-                    m: MCaptchaBuilder::default()
-                        .defense(mcaptcha.m.get_defense())
-                        .duration(mcaptcha.get_duration())
-                        .build()?,
-                };
-
-                Self::add_captcha_runner(ctx, &new_name, mcaptcha)?;
-                Self::delete_captcha_runner(ctx, &key_name)?;
-            }
+        let mcaptcha = match Self::get_mut_mcaptcha(&key)? {
+            Some(mcaptcha) => mcaptcha.clone(),
+            None => return Err(CacheError::CaptchaNotFound.into()),
         };
+        let new_key = ctx.open_key_writable(&RedisString::create_from_slice(
+            ctx.ctx,
+            new_name.as_bytes(),
+        ));
+        if new_key.key_type() != KeyType::Empty {
+            return Err(CacheError::DuplicateCaptcha.into());
+        }
+
+        let duration = mcaptcha.get_duration();
+        let needs_safety = !mcaptcha.is_sliding_window();
+        let scheduled = mcaptcha.get_scheduled().clone();
+        new_key.set_value(&MCAPTCHA_MCAPTCHA_TYPE, mcaptcha)?;
+        metrics::MCAPTCHAS.incr();
+        key.delete()?;
 
+        Bucket::rename_captcha(ctx, &scheduled, &key_name, &new_name)?;
+        MCaptchaSafety::delete(ctx, &key_name)?;
+        if needs_safety {
+            MCaptchaSafety::new(ctx, duration, &new_name)?;
+        }
+        log_debug(
+            ctx,
+            &format!("mcaptcha {} renamed to {}", key_name, new_name),
+        );
+        ctx.replicate_verbatim();
         REDIS_OK
     }
 
-    /// delete captcha
+    /// delete captcha along with its safety and pending decrements. Outstanding challenges of
+    /// the captcha are deleted when `CHALLENGES` is passed. Replies with a summary of what was
+    /// removed
     pub fn delete_captcha(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
         let mut args = args.into_iter().skip(1);
-        let key_name = get_captcha_key(&args.next_string()?);
-        Self::delete_captcha_runner(ctx, &key_name)
+        let captcha = args.next_string()?;
+        let with_challenges = match args.next() {
+            None => false,
+            Some(arg) if arg.to_string_lossy().eq_ignore_ascii_case("CHALLENGES") => true,
+            Some(arg) => {
+                return Err(
+                    CacheError::new(format!("Unknown argument: {}", arg.to_string_lossy())).into(),
+                )
+            }
+        };
+        args.done()?;
+
+        let key_name = get_captcha_key(&captcha);
+        let scheduled = Self::delete_captcha_runner(ctx, &key_name)?;
+
+        let safety = MCaptchaSafety::delete(ctx, &key_name)?;
+        let decrements = Bucket::purge_captcha(ctx, &scheduled, &key_name)?;
+        let challenges = if with_challenges {
+            Some(Challenge::delete_all(ctx, &captcha)?)
+        } else {
+            None
+        };
+        log_debug(ctx, &format!("mcaptcha {} deleted", key_name));
+
+        let summary: BTreeMap<RedisValueKey, RedisValue> = [
+            ("safety", RedisValue::Integer(safety.into())),
+            ("pending_decrements", RedisValue::Integer(decrements as i64)),
+            (
+                "challenges",
+                challenges.map_or(RedisValue::Null, |c| RedisValue::Integer(c as i64)),
+            ),
+        ]
+        .into_iter()
+        .map(|(name, val)| (RedisValueKey::String(name.into()), val))
+        .collect();
+        ctx.replicate_verbatim();
+        Ok(RedisValue::OrderedMap(summary))
     }
 
+    /// delete captcha key. Returns buckets with pending decrements of the deleted mCaptcha
     #[inline]
-    fn delete_captcha_runner(ctx: &Context, key_name: &str) -> RedisResult {
+    fn delete_captcha_runner(
+        ctx: &Context,
+        key_name: &str,
+    ) -> CacheResult<BTreeSet<(u64, String)>> {
         let key = ctx.open_key_writable(&RedisString::create_from_slice(
             ctx.ctx,
             key_name.as_bytes(),
         ));
         if key.key_type() == KeyType::Empty {
-            Err(RedisError::nonexistent_key())
-        } else {
-            key.delete()?;
-            REDIS_OK
+            return Err(RedisError::nonexistent_key().into());
         }
+        let scheduled = match Self::get_mut_mcaptcha(&key) {
+            Ok(Some(mcaptcha)) => std::mem::take(&mut mcaptcha.scheduled),
+            _ => BTreeSet::new(),
+        };
+        key.delete()?;
+        Ok(scheduled)
+    }
+
+    /// replicate mCaptcha stored under `key_name` as `RESTORE_CAPTCHA`. Writes that depend on
+    /// the clock replicate their effect instead of the command
+    pub fn replicate(&self, ctx: &Context, key_name: &str) -> CacheResult<()> {
+        let data = serde_json::to_string(self)?;
+        ctx.replicate("MCAPTCHA_CACHE.RESTORE_CAPTCHA", &[key_name, data.as_str()]);
+        Ok(())
+    }
+
+    /// Restore mCaptcha from its serialized form. Emitted during AOF rewrite and replicated by
+    /// [MCaptcha::replicate]
+    pub fn restore(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+        let mut args = args.into_iter().skip(1);
+        let key_name = args.next_string()?;
+        let data = args.next_string()?;
+        let mut mcaptcha: MCaptcha = Format::Json.parse_str(&data)?;
+
+        let key = ctx.open_key_writable(&RedisString::create_from_slice(
+            ctx.ctx,
+            key_name.as_bytes(),
+        ));
+        check_restore(ctx, &key)?;
+        // scheduled decrements aren't serialized, those of the replaced mCaptcha that are still
+        // pending are kept
+        if let Some(replaced) = Self::get_mut_mcaptcha(&key)? {
+            mcaptcha.scheduled = std::mem::take(&mut replaced.scheduled);
+            mcaptcha
+                .scheduled
+                .retain(|(_, bucket_name)| Bucket::is_pending(ctx, bucket_name, &key_name));
+        }
+        key.set_value(&MCAPTCHA_MCAPTCHA_TYPE, mcaptcha)?;
+        metrics::MCAPTCHAS.incr();
+        ctx.replicate_verbatim();
+        REDIS_OK
     }
 }
 
@@ -234,13 +739,13 @@ pub static MCAPTCHA_MCAPTCHA_TYPE: RedisType = RedisType::new(
         version: raw::REDISMODULE_TYPE_METHOD_VERSION as u64,
         rdb_load: Some(type_methods::rdb_load),
         rdb_save: Some(type_methods::rdb_save),
-        aof_rewrite: None,
+        aof_rewrite: Some(type_methods::aof_rewrite),
         free: Some(type_methods::free),
+        mem_usage: Some(type_methods::mem_usage),
+        digest: Some(type_methods::digest),
 
         // Currently unused by Redis
-        mem_usage: None,
         mem_usage2: None,
-        digest: None,
 
         // Aux data
         aux_load: None,
@@ -252,13 +757,14 @@ pub static MCAPTCHA_MCAPTCHA_TYPE: RedisType = RedisType::new(
         free_effort2: None,
         unlink: None,
         unlink2: None,
-        copy: None,
+        copy: Some(type_methods::copy),
         copy2: None,
-        defrag: None,
+        defrag: Some(type_methods::defrag),
     },
 );
 
 pub mod type_methods {
+    use std::mem::size_of;
     use std::os::raw::c_void;
 
     use libc::c_int;
@@ -267,37 +773,210 @@ pub mod type_methods {
 
     #[allow(non_snake_case, unused)]
     pub extern "C" fn rdb_load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> *mut c_void {
-        let mcaptcha = match encver {
-            0 => {
-                let data = raw::load_string(rdb).unwrap().to_string();
-                let mcaptcha: Result<MCaptcha, CacheError> = Format::Json.from_str(&data);
-                if mcaptcha.is_err() {
-                    panic!(
-                        "Can't load mCaptcha from old redis RDB, error while serde {}, data received: {}",
-                        mcaptcha.err().unwrap(),
-                        data
-                    );
-                }
-                mcaptcha.unwrap()
-            }
-            _ => panic!("Can't load mCaptcha from old redis RDB, encver {}", encver),
+        let value = match load(rdb, encver) {
+            Ok(mcaptcha) => Box::into_raw(Box::new(mcaptcha)) as *mut c_void,
+            Err(e) => quarantine::on_load_error(
+                rdb,
+                &MCAPTCHA_MCAPTCHA_TYPE,
+                "mCaptcha",
+                encver,
+                e,
+                placeholder,
+            ),
         };
+        if !value.is_null() {
+            metrics::MCAPTCHAS.incr();
+        }
+        value
+    }
 
-        Box::into_raw(Box::new(mcaptcha)) as *mut c_void
+    fn load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> CacheResult<MCaptcha> {
+        match encver {
+            0 => Format::Json.parse_slice(raw::load_string(rdb)?.as_slice()),
+            1..=4 => load_fields(rdb, encver),
+            _ => Err(CacheError::new(format!(
+                "Can't load mCaptcha from unknown encver: {}",
+                encver
+            ))),
+        }
+    }
+
+    /// stands in for an mCaptcha that couldn't be loaded until it is purged from keyspace
+    fn placeholder() -> MCaptcha {
+        let payload = CreateMCaptcha {
+            levels: vec![Level {
+                visitor_threshold: 1,
+                difficulty_factor: 1,
+            }],
+            duration: 1.0,
+            mode: Mode::LeakyBucket,
+        };
+        MCaptcha::new(payload).expect("placeholder mCaptcha is valid")
     }
 
     pub unsafe extern "C" fn free(value: *mut c_void) {
         let val = value as *mut MCaptcha;
-        Box::from_raw(val);
+        drop(Box::from_raw(val));
+        metrics::MCAPTCHAS.decr();
+    }
+
+    /// estimate of memory used by an mCaptcha, including its defense levels
+    pub unsafe extern "C" fn mem_usage(value: *const c_void) -> usize {
+        let mcaptcha = &*(value as *const MCaptcha);
+        let window = mcaptcha.window.as_ref().map_or(0, |w| w.mem_usage());
+        let scheduled: usize = mcaptcha
+            .scheduled
+            .iter()
+            .map(|(_, bucket_name)| size_of::<(u64, String)>() + bucket_name.capacity())
+            .sum();
+        size_of::<MCaptcha>()
+            + mcaptcha.get_levels().len() * size_of::<Level>()
+            + window
+            + scheduled
+    }
+
+    /// defense levels, duration, visitor count and sliding window
+    pub unsafe extern "C" fn digest(md: *mut raw::RedisModuleDigest, value: *mut c_void) {
+        let mcaptcha = &*(value as *mut MCaptcha);
+        for level in mcaptcha.get_levels().iter() {
+            digest_add_u64(md, level.visitor_threshold as u64);
+            digest_add_u64(md, level.difficulty_factor as u64);
+        }
+        digest_add_u64(md, mcaptcha.get_duration_ms());
+        digest_add_u64(md, mcaptcha.get_visitors() as u64);
+        if let Some(window) = mcaptcha.window.as_ref() {
+            digest_add_u64(md, window.get_head());
+            for count in window.get_slots() {
+                digest_add_u64(md, *count as u64);
+            }
+        }
+        digest_end_sequence(md);
+    }
+
+    /// Pending decrements stay with the original, buckets only decrement the key they were
+    /// scheduled for
+    pub unsafe extern "C" fn copy(
+        _fromkey: *mut raw::RedisModuleString,
+        _tokey: *mut raw::RedisModuleString,
+        value: *const c_void,
+    ) -> *mut c_void {
+        let mut mcaptcha = (*(value as *const MCaptcha)).clone();
+        mcaptcha.scheduled.clear();
+        metrics::MCAPTCHAS.incr();
+        Box::into_raw(Box::new(mcaptcha)) as *mut c_void
+    }
+
+    /// Moves the boxed mCaptcha and its sliding window slots
+    pub unsafe extern "C" fn defrag(
+        ctx: *mut raw::RedisModuleDefragCtx,
+        _key: *mut raw::RedisModuleString,
+        value: *mut *mut c_void,
+    ) -> c_int {
+        let moved = defrag_alloc(ctx, *value);
+        if !moved.is_null() {
+            *value = moved;
+        }
+        let mcaptcha = &mut *(*value as *mut MCaptcha);
+        if let Some(window) = mcaptcha.window.as_mut() {
+            defrag_vec(ctx, window.get_slots_mut());
+        }
+        // defense levels are private to libmcaptcha and can only be reallocated by a clone, so
+        // that's only done once the mCaptcha itself was moved
+        if !moved.is_null() {
+            mcaptcha.m = mcaptcha.m.clone();
+        }
+        0
     }
 
     #[allow(non_snake_case, unused)]
     pub unsafe extern "C" fn rdb_save(rdb: *mut raw::RedisModuleIO, value: *mut c_void) {
         let mcaptcha = &*(value as *mut MCaptcha);
-        match &serde_json::to_string(mcaptcha) {
-            Ok(string) => raw::save_string(rdb, string),
+        match &Format::Bincode.to_vec(&mcaptcha.m) {
+            Ok(buf) => raw::save_slice(rdb, buf),
             Err(e) => panic!("error while rdb_save: {}", e),
         }
+        raw::save_unsigned(rdb, mcaptcha.duration_ms.unwrap_or(0));
+        match &mcaptcha.window {
+            Some(window) => {
+                raw::save_unsigned(rdb, 1);
+                raw::save_unsigned(rdb, window.get_slot_ms());
+                raw::save_unsigned(rdb, window.get_head());
+                raw::save_unsigned(rdb, window.get_slots().len() as u64);
+                for count in window.get_slots() {
+                    raw::save_unsigned(rdb, *count as u64);
+                }
+            }
+            None => raw::save_unsigned(rdb, 0),
+        }
+        let stats = &mcaptcha.stats;
+        raw::save_unsigned(rdb, stats.peak_visitors.into());
+        raw::save_unsigned(rdb, stats.total_visitors);
+        raw::save_unsigned(rdb, stats.challenges_issued);
+        raw::save_unsigned(rdb, stats.challenges_verified);
+        raw::save_unsigned(rdb, stats.last_visit);
+    }
+
+    /// load mCaptcha saved by [rdb_save]. Fields are appended to newer encoding versions. All
+    /// fields are read before they are decoded, so that an mCaptcha that can't be loaded doesn't
+    /// leave the rest of the RDB misaligned
+    fn load_fields(rdb: *mut raw::RedisModuleIO, encver: c_int) -> CacheResult<MCaptcha> {
+        let buf = raw::load_string_buffer(rdb)?;
+        let duration_ms = if encver >= 2 {
+            Some(raw::load_unsigned(rdb)?).filter(|ms| *ms != 0)
+        } else {
+            None
+        };
+        let window = if encver >= 3 && raw::load_unsigned(rdb)? == 1 {
+            let slot_ms = raw::load_unsigned(rdb)?;
+            let head = raw::load_unsigned(rdb)?;
+            let len = raw::load_unsigned(rdb)?;
+            let mut slots = Vec::with_capacity(len.min(64) as usize);
+            for _ in 0..len {
+                slots.push(raw::load_unsigned(rdb)? as u32);
+            }
+            Some((slot_ms, head, slots))
+        } else {
+            None
+        };
+        let stats = if encver >= 4 {
+            Stats {
+                peak_visitors: raw::load_unsigned(rdb)? as u32,
+                total_visitors: raw::load_unsigned(rdb)?,
+                challenges_issued: raw::load_unsigned(rdb)?,
+                challenges_verified: raw::load_unsigned(rdb)?,
+                last_visit: raw::load_unsigned(rdb)?,
+            }
+        } else {
+            Stats::default()
+        };
+
+        let m = Format::Bincode.parse_slice(buf.as_ref())?;
+        let window = match window {
+            Some((slot_ms, _, slots)) if slot_ms == 0 || slots.is_empty() => {
+                return Err(CacheError::new("Invalid sliding window".into()));
+            }
+            Some((slot_ms, head, slots)) => Some(SlidingWindow::from_parts(slot_ms, head, slots)),
+            None => None,
+        };
+        Ok(MCaptcha {
+            m,
+            duration_ms,
+            window,
+            stats,
+            scheduled: BTreeSet::new(),
+        })
+    }
+
+    pub unsafe extern "C" fn aof_rewrite(
+        aof: *mut raw::RedisModuleIO,
+        key: *mut raw::RedisModuleString,
+        value: *mut c_void,
+    ) {
+        let mcaptcha = &*(value as *mut MCaptcha);
+        match &serde_json::to_string(mcaptcha) {
+            Ok(string) => emit_aof(aof, "MCAPTCHA_CACHE.RESTORE_CAPTCHA", key, string),
+            Err(e) => panic!("error while aof_rewrite: {}", e),
+        }
     }
 }
 
@@ -348,7 +1027,8 @@ mod tests {
         let levels = get_levels();
         let payload = CreateMCaptcha {
             levels,
-            duration: 30,
+            duration: 30.0,
+            mode: Mode::LeakyBucket,
         };
 
         let mcaptcha = MCaptcha::new(payload);
@@ -360,12 +1040,14 @@ mod tests {
         }
         assert_eq!(mcaptcha.get_visitors(), 50);
         assert_eq!(mcaptcha.get_difficulty(), 50);
+        assert_eq!(mcaptcha.get_level(), (0, Some(50)));
 
         for _ in 0..451 {
             mcaptcha.add_visitor();
         }
         assert_eq!(mcaptcha.get_visitors(), 501);
         assert_eq!(mcaptcha.get_difficulty(), 5000);
+        assert_eq!(mcaptcha.get_level(), (1, Some(500)));
 
         mcaptcha.decrement_visitor_by(501);
         for _ in 0..5002 {
@@ -373,5 +1055,210 @@ mod tests {
         }
         assert_eq!(mcaptcha.get_visitors(), 5002);
         assert_eq!(mcaptcha.get_difficulty(), 50000);
+        assert_eq!(mcaptcha.get_level(), (2, Some(5000)));
+    }
+
+    #[test]
+    fn binary_encoding_works() {
+        let payload = CreateMCaptcha {
+            levels: get_levels(),
+            duration: 30.0,
+            mode: Mode::LeakyBucket,
+        };
+        let mut mcaptcha = MCaptcha::new(payload).unwrap();
+        for _ in 0..501 {
+            mcaptcha.add_visitor();
+        }
+
+        let buf = Format::Bincode.to_vec(&mcaptcha.m).unwrap();
+        let json = Format::Json.to_vec(&mcaptcha.m).unwrap();
+        assert!(buf.len() < json.len());
+
+        let loaded = MCaptcha {
+            m: Format::Bincode.parse_slice(&buf).unwrap(),
+            duration_ms: None,
+            window: None,
+            stats: Stats::default(),
+            scheduled: BTreeSet::new(),
+        };
+        assert_eq!(loaded.get_visitors(), mcaptcha.get_visitors());
+        assert_eq!(loaded.get_difficulty(), mcaptcha.get_difficulty());
+        assert_eq!(loaded.get_duration(), mcaptcha.get_duration());
+    }
+
+    #[test]
+    fn fractional_duration_works() {
+        let payload: CreateMCaptcha = Format::Json
+            .parse_str(r#"{"levels": [{"visitor_threshold": 50, "difficulty_factor": 50}], "duration": 0.25}"#)
+            .unwrap();
+        let mcaptcha = MCaptcha::new(payload).unwrap();
+        assert_eq!(mcaptcha.get_duration_ms(), 250);
+        assert_eq!(mcaptcha.get_duration(), 1);
+
+        let payload = CreateMCaptcha {
+            levels: get_levels(),
+            duration: 30.0,
+            mode: Mode::LeakyBucket,
+        };
+        let mcaptcha = MCaptcha::new(payload).unwrap();
+        assert_eq!(mcaptcha.get_duration_ms(), 30_000);
+        assert_eq!(mcaptcha.get_duration(), 30);
+
+        let payload = CreateMCaptcha {
+            levels: get_levels(),
+            duration: 0.0,
+            mode: Mode::LeakyBucket,
+        };
+        assert!(MCaptcha::new(payload).is_err());
+    }
+
+    #[test]
+    fn sliding_window_mode_works() {
+        const NOW: u64 = 1_000_000;
+        let payload: CreateMCaptcha = Format::Json
+            .parse_str(r#"{"levels": [{"visitor_threshold": 50, "difficulty_factor": 50}, {"visitor_threshold": 500, "difficulty_factor": 500}], "duration": 1, "mode": "sliding_window"}"#)
+            .unwrap();
+        let mut mcaptcha = MCaptcha::new(payload).unwrap();
+        assert!(mcaptcha.is_sliding_window());
+
+        mcaptcha.register_visitors(NOW, 51).unwrap();
+        assert_eq!(mcaptcha.get_difficulty(), 500);
+        assert_eq!(mcaptcha.get_visitors_at(NOW + 500), 51);
+        assert_eq!(mcaptcha.get_visitors_at(NOW + 2000), 0);
+        assert_eq!(mcaptcha.at(NOW + 2000).get_visitors(), 0);
+        assert!(matches!(mcaptcha.at(NOW + 500), Cow::Borrowed(_)));
+        assert_eq!(mcaptcha.get_visitors(), 51);
+
+        mcaptcha.register_visitors(NOW + 2000, 1).unwrap();
+        assert_eq!(mcaptcha.get_visitors(), 1);
+        assert_eq!(mcaptcha.get_difficulty(), 50);
+    }
+
+    #[test]
+    fn add_visitors_works() {
+        let payload = CreateMCaptcha {
+            levels: get_levels(),
+            duration: 30.0,
+            mode: Mode::LeakyBucket,
+        };
+        let mut mcaptcha = MCaptcha::new(payload).unwrap();
+        let mut expected = mcaptcha.clone();
+        for (added, decremented) in [
+            (1, 0),
+            (60, 0),
+            (7, 20),
+            (480, 400),
+            (3, 600),
+            (6000, 0),
+            (2, 5990),
+            (600_000, 0),
+            (45, 1_000_000),
+        ] {
+            mcaptcha.add_visitors(added).unwrap();
+            for _ in 0..added {
+                expected.add_visitor();
+            }
+            assert_eq!(mcaptcha.get_visitors(), expected.get_visitors());
+            assert_eq!(mcaptcha.get_difficulty(), expected.get_difficulty());
+            assert_eq!(mcaptcha.get_level(), expected.get_level());
+
+            mcaptcha.decrement_visitor_by(decremented);
+            expected.decrement_visitor_by(decremented);
+        }
+
+        mcaptcha.add_visitors(10).unwrap();
+        assert!(mcaptcha.add_visitors(u32::MAX - 9).is_err());
+        assert_eq!(mcaptcha.get_visitors(), 10);
+    }
+
+    #[test]
+    fn next_decrement_works() {
+        const NOW: u64 = 1_000_000;
+        let payload = CreateMCaptcha {
+            levels: get_levels(),
+            duration: 30.0,
+            mode: Mode::LeakyBucket,
+        };
+        let mut mcaptcha = MCaptcha::new(payload).unwrap();
+        assert_eq!(mcaptcha.next_decrement(NOW), None);
+
+        let first = get_bucket_name(NOW + 30_000);
+        let second = get_bucket_name(NOW + 31_000);
+        mcaptcha.register_visitors(NOW, 2).unwrap();
+        mcaptcha.schedule_decrement(NOW + 30_000, &first);
+        mcaptcha.register_visitors(NOW + 1000, 1).unwrap();
+        mcaptcha.schedule_decrement(NOW + 31_000, &second);
+        assert_eq!(mcaptcha.next_decrement(NOW), Some(NOW + 30_000));
+        assert_eq!(mcaptcha.get_scheduled().len(), 2);
+
+        mcaptcha.run_decrement(NOW + 30_000, &first, 2);
+        assert_eq!(mcaptcha.get_visitors(), 1);
+        assert_eq!(mcaptcha.next_decrement(NOW), Some(NOW + 31_000));
+
+        mcaptcha.run_decrement(NOW + 31_000, &second, 1);
+        assert_eq!(mcaptcha.next_decrement(NOW), None);
+    }
+
+    #[test]
+    fn stats_works() {
+        const NOW: u64 = 1_000_000;
+        let payload = CreateMCaptcha {
+            levels: get_levels(),
+            duration: 30.0,
+            mode: Mode::LeakyBucket,
+        };
+        let mut mcaptcha = MCaptcha::new(payload).unwrap();
+        assert_eq!(mcaptcha.get_stats(), &Stats::default());
+
+        mcaptcha.register_visitors(NOW, 10).unwrap();
+        mcaptcha.decrement_visitor_by(10);
+        mcaptcha.register_visitors(NOW + 1, 3).unwrap();
+        mcaptcha.challenge_issued();
+        mcaptcha.challenge_verified();
+
+        let stats = mcaptcha.get_stats();
+        assert_eq!(stats.peak_visitors, 10);
+        assert_eq!(stats.total_visitors, 13);
+        assert_eq!(stats.challenges_issued, 1);
+        assert_eq!(stats.challenges_verified, 1);
+        assert_eq!(stats.last_visit, NOW + 1);
+    }
+
+    #[test]
+    fn update_works() {
+        const NOW: u64 = 1_000_000;
+        let payload = CreateMCaptcha {
+            levels: get_levels(),
+            duration: 30.0,
+            mode: Mode::LeakyBucket,
+        };
+        let mut mcaptcha = MCaptcha::new(payload).unwrap();
+        mcaptcha.register_visitors(NOW, 501).unwrap();
+        assert_eq!(mcaptcha.get_difficulty(), 5000);
+
+        let update: UpdateMCaptcha = Format::Json
+            .parse_str(r#"{"levels": [{"visitor_threshold": 100, "difficulty_factor": 10}, {"visitor_threshold": 1000, "difficulty_factor": 100}]}"#)
+            .unwrap();
+        mcaptcha.update(update, NOW).unwrap();
+        assert_eq!(mcaptcha.get_visitors(), 501);
+        assert_eq!(mcaptcha.get_difficulty(), 100);
+        assert_eq!(mcaptcha.get_duration_ms(), 30_000);
+        assert_eq!(mcaptcha.get_stats().total_visitors, 501);
+
+        let update: UpdateMCaptcha = Format::Json
+            .parse_str(r#"{"levels": [{"visitor_threshold": 1000, "difficulty_factor": 10}], "duration": 0.5}"#)
+            .unwrap();
+        mcaptcha.update(update, NOW).unwrap();
+        assert_eq!(mcaptcha.get_difficulty(), 10);
+        assert_eq!(mcaptcha.get_duration_ms(), 500);
+        assert!(!mcaptcha.is_sliding_window());
+
+        // levels must be well-formed, mCaptcha is left as is otherwise
+        let update = UpdateMCaptcha {
+            levels: Vec::new(),
+            duration: None,
+        };
+        assert!(mcaptcha.update(update, NOW).is_err());
+        assert_eq!(mcaptcha.get_visitors(), 501);
     }
 }
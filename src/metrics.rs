@@ -0,0 +1,94 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Module metrics, reported in the `mcaptcha_cache_metrics` section of `INFO`. Gauges of live
+//! values are incremented when a value enters the keyspace and decremented by `free` callbacks
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use redis_module::server_events::INFO_COMMAND_HANDLER_LIST;
+use redis_module::{InfoContext, RedisResult};
+
+/// gauge or counter
+pub struct Metric(AtomicI64);
+
+impl Metric {
+    const fn new() -> Self {
+        Self(AtomicI64::new(0))
+    }
+
+    #[inline]
+    pub fn incr(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn decr(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n as i64, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// live mCaptchas
+pub static MCAPTCHAS: Metric = Metric::new();
+/// live buckets
+pub static BUCKETS: Metric = Metric::new();
+/// bucket and safety timers that haven't gone off yet
+pub static TIMERS: Metric = Metric::new();
+/// live challenges
+pub static CHALLENGES: Metric = Metric::new();
+/// live verification tokens
+pub static TOKENS: Metric = Metric::new();
+/// live safeties
+pub static SAFETIES: Metric = Metric::new();
+/// visitors added to mCaptchas
+pub static VISITORS_ADDED: Metric = Metric::new();
+/// bucket decrements applied to mCaptchas
+pub static DECREMENTS_EXECUTED: Metric = Metric::new();
+/// mCaptchas protected again after their safety expired
+pub static SAFETY_RECOVERIES: Metric = Metric::new();
+/// verification tokens validated for the captcha they were issued for
+pub static TOKENS_VALIDATED: Metric = Metric::new();
+/// values that couldn't be loaded from RDB
+pub static RDB_LOAD_ERRORS: Metric = Metric::new();
+
+#[linkme::distributed_slice(INFO_COMMAND_HANDLER_LIST)]
+fn info(ctx: &InfoContext, _for_crash_report: bool) -> RedisResult<()> {
+    ctx.builder()
+        .add_section("metrics")
+        .field("mcaptchas", MCAPTCHAS.get())?
+        .field("buckets", BUCKETS.get())?
+        .field("timers", TIMERS.get())?
+        .field("challenges", CHALLENGES.get())?
+        .field("tokens", TOKENS.get())?
+        .field("safeties", SAFETIES.get())?
+        .field("visitors_added", VISITORS_ADDED.get())?
+        .field("decrements_executed", DECREMENTS_EXECUTED.get())?
+        .field("safety_recoveries", SAFETY_RECOVERIES.get())?
+        .field("tokens_validated", TOKENS_VALIDATED.get())?
+        .field("rdb_load_errors", RDB_LOAD_ERRORS.get())?
+        .build_section()?
+        .build_info()?;
+    Ok(())
+}
@@ -0,0 +1,220 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Values that couldn't be loaded from RDB. Depending on [LoadPolicy], loading either fails or
+//! the corrupt value is replaced with a placeholder, recorded in quarantine and removed from the
+//! keyspace once loading is complete. Values are only quarantined while the server is loading,
+//! corrupt payloads passed to `RESTORE` are always refused.
+//!
+//! A value can only be skipped when all of it was read. Loaders read every field before decoding
+//! any of them, but values of an unknown encoding version, or that are cut short, can't be read
+//! in full. They leave the RDB misaligned and Redis aborts loading when it doesn't find the end
+//! of the value, whatever the policy.
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use redis_module::logging::{log_io_error, RedisLogLevel};
+use redis_module::native_types::RedisType;
+use redis_module::{
+    raw, Context, ContextFlags, EnumConfigurationValue, RedisError, RedisResult, RedisString,
+    RedisValue,
+};
+
+use crate::config::{self, log_warning};
+use crate::errors::*;
+use crate::metrics;
+
+/// what to do when a value can't be loaded from RDB
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadPolicy {
+    /// report load error to Redis
+    Fail,
+    /// skip corrupt value and record it in quarantine
+    Quarantine,
+}
+
+impl LoadPolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LoadPolicy::Fail => "fail",
+            LoadPolicy::Quarantine => "quarantine",
+        }
+    }
+}
+
+impl TryFrom<i32> for LoadPolicy {
+    type Error = RedisError;
+
+    fn try_from(v: i32) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(LoadPolicy::Fail),
+            1 => Ok(LoadPolicy::Quarantine),
+            _ => Err(RedisError::Str("Unsupported RDB load policy")),
+        }
+    }
+}
+
+impl From<LoadPolicy> for i32 {
+    fn from(policy: LoadPolicy) -> Self {
+        policy as i32
+    }
+}
+
+impl EnumConfigurationValue for LoadPolicy {
+    fn get_options(&self) -> (Vec<String>, Vec<i32>) {
+        [LoadPolicy::Fail, LoadPolicy::Quarantine]
+            .iter()
+            .map(|policy| (policy.name().to_string(), i32::from(*policy)))
+            .unzip()
+    }
+}
+
+/// value that couldn't be loaded from RDB
+#[derive(Clone)]
+struct QuarantinedValue {
+    key: String,
+    db: c_int,
+    type_name: &'static str,
+    encver: c_int,
+    error: String,
+    /// type and address of placeholder, a key is only removed while it still holds it
+    redis_type: &'static RedisType,
+    placeholder: usize,
+    /// placeholder has been removed from keyspace
+    purged: bool,
+}
+
+impl From<&QuarantinedValue> for RedisValue {
+    fn from(v: &QuarantinedValue) -> Self {
+        RedisValue::Array(vec![
+            v.key.as_str().into(),
+            RedisValue::Integer(v.db.into()),
+            v.type_name.into(),
+            RedisValue::Integer(v.encver.into()),
+            v.error.as_str().into(),
+        ])
+    }
+}
+
+lazy_static! {
+    static ref QUARANTINE: Mutex<Vec<QuarantinedValue>> = Mutex::new(Vec::new());
+}
+
+/// called by `rdb_load` callbacks when a value can't be loaded. Returns null when loading should
+/// fail and a placeholder, that will be removed when loading is complete, otherwise. Values
+/// aren't quarantined outside of loading, `RESTORE` of a corrupt value fails
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn on_load_error<T>(
+    rdb: *mut raw::RedisModuleIO,
+    redis_type: &'static RedisType,
+    type_name: &'static str,
+    encver: c_int,
+    error: CacheError,
+    placeholder: fn() -> T,
+) -> *mut c_void {
+    let key = unsafe { raw::RedisModule_GetKeyNameFromIO.unwrap()(rdb) };
+    let key = if key.is_null() {
+        String::new()
+    } else {
+        String::from_utf8_lossy(RedisString::string_as_slice(key)).into_owned()
+    };
+    let db = unsafe { raw::RedisModule_GetDbIdFromIO.unwrap()(rdb) };
+
+    log_io_error(
+        rdb,
+        RedisLogLevel::Warning,
+        &format!(
+            "Can't load {} {} from RDB, encver {}: {}",
+            type_name, key, encver, error
+        ),
+    );
+
+    metrics::RDB_LOAD_ERRORS.incr();
+    let ctx = Context::new(unsafe { raw::RedisModule_GetContextFromIO.unwrap()(rdb) });
+    if !ctx.get_flags().contains(ContextFlags::LOADING) {
+        return ptr::null_mut();
+    }
+    match config::rdb_load_policy() {
+        LoadPolicy::Fail => ptr::null_mut(),
+        LoadPolicy::Quarantine => {
+            let value = Box::into_raw(Box::new(placeholder())) as *mut c_void;
+            QUARANTINE.lock().unwrap().push(QuarantinedValue {
+                key,
+                db,
+                type_name,
+                encver,
+                error: error.to_string(),
+                redis_type,
+                placeholder: value as usize,
+                purged: false,
+            });
+            value
+        }
+    }
+}
+
+/// remove placeholders of quarantined values from keyspace. Runs when loading is complete
+pub fn purge(ctx: &Context) {
+    let mut quarantine = QUARANTINE.lock().unwrap();
+    let selected_db = unsafe { raw::RedisModule_GetSelectedDb.unwrap()(ctx.ctx) };
+    for val in quarantine.iter_mut().filter(|val| !val.purged) {
+        unsafe { raw::RedisModule_SelectDb.unwrap()(ctx.ctx, val.db) };
+        let key =
+            ctx.open_key_writable(&RedisString::create_from_slice(ctx.ctx, val.key.as_bytes()));
+        // key may have been overwritten since it was loaded
+        let holds_placeholder = matches!(
+            key.get_value::<c_void>(val.redis_type),
+            Ok(Some(value)) if &*value as *const c_void as usize == val.placeholder
+        );
+        if holds_placeholder {
+            if let Err(e) = key.delete() {
+                log_warning(
+                    ctx,
+                    &format!("error while removing quarantined key {}: {}", val.key, e),
+                );
+                continue;
+            }
+        }
+        val.purged = true;
+        log_warning(
+            ctx,
+            &format!(
+                "quarantined {} {} in db {}: {}",
+                val.type_name, val.key, val.db, val.error
+            ),
+        );
+    }
+    unsafe { raw::RedisModule_SelectDb.unwrap()(ctx.ctx, selected_db) };
+}
+
+/// list values that couldn't be loaded from RDB. `CLEAR` empties the list
+pub fn quarantine(_ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+    let mut args = args.into_iter().skip(1);
+    let mut quarantine = QUARANTINE.lock().unwrap();
+    match args.next() {
+        None => Ok(RedisValue::Array(
+            quarantine.iter().map(RedisValue::from).collect(),
+        )),
+        Some(arg) if arg.to_string_lossy().eq_ignore_ascii_case("CLEAR") => {
+            let len = quarantine.len();
+            quarantine.clear();
+            Ok(RedisValue::Integer(len as i64))
+        }
+        Some(arg) => Err(CacheError::new(format!("Unknown argument: {}", arg)).into()),
+    }
+}
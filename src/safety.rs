@@ -20,20 +20,27 @@ use std::time::Duration;
 
 use redis_module::key::RedisKeyWritable;
 use redis_module::native_types::RedisType;
-use redis_module::raw::KeyType;
+use redis_module::raw::{KeyType, RedisModuleTimerID};
 use redis_module::{raw, Context};
-use redis_module::{NotifyEvent, RedisString};
+use redis_module::{NextArg, NotifyEvent, RedisResult, RedisString, REDIS_OK};
 use serde::{Deserialize, Serialize};
 
 use crate::bucket::Bucket;
+use crate::config::{self, log_debug, log_warning};
 use crate::errors::*;
 use crate::mcaptcha::MCaptcha;
+use crate::metrics;
+use crate::quarantine;
 use crate::utils::*;
 
 const MCAPTCHA_SAFETY_VERSION: i32 = 0;
 
-#[derive(Serialize, Deserialize)]
-pub struct MCaptchaSafety;
+#[derive(Default, Serialize, Deserialize)]
+pub struct MCaptchaSafety {
+    /// timer that boosts safety. Timers don't survive restarts, so it isn't persisted
+    #[serde(skip)]
+    timer: Option<RedisModuleTimerID>,
+}
 
 impl MCaptchaSafety {
     /// When safety is deleted due to expiration, if mcaptcha exists in cache a new safety should
@@ -47,58 +54,94 @@ impl MCaptchaSafety {
         if mcaptcha_name.is_none() {
             return;
         }
-        let mcaptcha_name = mcaptcha_name.unwrap();
-        let mcaptcha = ctx.open_key(&RedisString::create_from_slice(
+        if Self::protect(ctx, mcaptcha_name.unwrap()) {
+            metrics::SAFETY_RECOVERIES.incr();
+        }
+    }
+
+    /// Creates safety for mcaptcha, if it doesn't already have one, and schedules decrement of
+    /// its current visitors so that mcaptcha is eventually consistent. Returns true if mcaptcha
+    /// has a safety
+    pub fn protect(ctx: &Context, mcaptcha_name: &str) -> bool {
+        let mcaptcha = ctx.open_key_writable(&RedisString::create_from_slice(
             ctx.ctx,
             mcaptcha_name.as_bytes(),
         ));
         if mcaptcha.key_type() == KeyType::Empty {
-            ctx.log_warning(&format!("mcaptcha {} is empty", mcaptcha_name));
-            return;
+            log_warning(ctx, &format!("mcaptcha {} is empty", mcaptcha_name));
+            return false;
         }
 
-        let mcaptcha_val = MCaptcha::get_mcaptcha(&mcaptcha);
+        let mcaptcha_val = MCaptcha::get_mut_mcaptcha(&mcaptcha);
         if mcaptcha_val.is_err() {
-            ctx.log_warning(&format!(
-                "error occurred while trying to access mcaptcha {}. error {} is empty",
-                mcaptcha_name,
-                mcaptcha_val.err().unwrap()
-            ));
-            return;
+            log_warning(
+                ctx,
+                &format!(
+                    "error occurred while trying to access mcaptcha {}. error {} is empty",
+                    mcaptcha_name,
+                    mcaptcha_val.err().unwrap()
+                ),
+            );
+            return false;
         }
         let mcaptcha_val = mcaptcha_val.unwrap();
         if mcaptcha_val.is_none() {
-            ctx.log_warning(&format!(
-                "error occurred while trying to access mcaptcha {}. is none",
-                mcaptcha_name,
-            ));
-            return;
+            log_warning(
+                ctx,
+                &format!(
+                    "error occurred while trying to access mcaptcha {}. is none",
+                    mcaptcha_name,
+                ),
+            );
+            return false;
         }
         let mcaptcha_val = mcaptcha_val.unwrap();
+        if mcaptcha_val.is_sliding_window() {
+            log_debug(
+                ctx,
+                &format!(
+                    "mcaptcha {} is in sliding window mode, safety not required",
+                    mcaptcha_name,
+                ),
+            );
+            return false;
+        }
         let duration = mcaptcha_val.get_duration();
         let visitors = mcaptcha_val.get_visitors();
 
-        if Self::new(ctx, duration, mcaptcha_name).is_err() {
-            ctx.log_warning(&format!(
-                "error occurred while creating safety for mcaptcha {}.",
-                mcaptcha_name,
-            ));
+        let protected = Self::new(ctx, duration, mcaptcha_name).is_ok();
+        if !protected {
+            log_warning(
+                ctx,
+                &format!(
+                    "error occurred while creating safety for mcaptcha {}.",
+                    mcaptcha_name,
+                ),
+            );
         };
         if visitors == 0 {
-            ctx.log_warning(&format!(
-                "visitors 0 for mcaptcha mcaptcha {}.",
-                mcaptcha_name,
-            ));
-            return;
+            log_warning(
+                ctx,
+                &format!("visitors 0 for mcaptcha mcaptcha {}.", mcaptcha_name),
+            );
+            return protected;
         }
 
-        match Bucket::increment_by(ctx, (mcaptcha_name.to_owned(), duration), visitors) {
-            Err(e) => ctx.log_warning(&format!("{}", e)),
-            Ok(()) => ctx.log_debug(&format!(
-                "Created new bucket making captcha {} eventually consistent",
-                &mcaptcha_name
-            )),
+        let duration_ms = mcaptcha_val.get_duration_ms();
+        match Bucket::increment_by(ctx, (mcaptcha_name.to_owned(), duration_ms), visitors) {
+            Err(e) => log_warning(ctx, &format!("{}", e)),
+            Ok((bucket_instant, bucket_name)) => {
+                mcaptcha_val.schedule_decrement(bucket_instant, &bucket_name);
+                log_debug(
+                    ctx,
+                    &format!(
+                        "Created new bucket making captcha {} eventually consistent",
+                        &mcaptcha_name
+                    ),
+                )
+            }
         }
+        protected
     }
 
     #[allow(clippy::new_ret_no_self)]
@@ -110,12 +153,12 @@ impl MCaptchaSafety {
         ));
 
         if safety.key_type() == KeyType::Empty {
-            let safety_val = MCaptchaSafety {};
-            safety.set_value(&MCAPTCHA_SAFETY_TYPE, safety_val)?;
-            ctx.log_debug(&format!("mcaptcha safety created: {}", safety_name));
+            safety.set_value(&MCAPTCHA_SAFETY_TYPE, MCaptchaSafety::default())?;
+            metrics::SAFETIES.incr();
+            log_debug(ctx, &format!("mcaptcha safety created: {}", safety_name));
             Self::set_timer(ctx, &safety, (safety_name, duration))?;
         } else {
-            ctx.log_debug(&format!("mcaptcha safety exists: {}", safety_name));
+            log_debug(ctx, &format!("mcaptcha safety exists: {}", safety_name));
         }
         Ok(())
     }
@@ -125,17 +168,24 @@ impl MCaptchaSafety {
         safety: &RedisKeyWritable,
         (safety_name, duration): (String, u64),
     ) -> CacheResult<()> {
-        let _ = ctx.create_timer(
+        let timer = ctx.create_timer(
             Duration::from_secs(duration),
             Self::boost,
             (safety_name, duration),
         );
-        safety.set_expire(Duration::from_secs(duration * 2))?;
+        metrics::TIMERS.incr();
+        if let Some(safety_val) = safety.get_value::<Self>(&MCAPTCHA_SAFETY_TYPE)? {
+            safety_val.timer = Some(timer);
+        }
+        safety.set_expire(Duration::from_secs(
+            duration * config::safety_expiry_multiplier(),
+        ))?;
         Ok(())
     }
 
     /// executes when timer goes off. Refreshes expiry timer and resets timer
     fn boost(ctx: &Context, (safety_name, duration): (String, u64)) {
+        metrics::TIMERS.decr();
         let safety = ctx.open_key_writable(&RedisString::create_from_slice(
             ctx.ctx,
             safety_name.as_bytes(),
@@ -147,7 +197,7 @@ impl MCaptchaSafety {
                 // if unable to create timer, then safety will expire and mcaptcha will be deleted
                 // as well. So when user requests pow config, there will be a cache miss, then
                 // config will be loaded from db. This is fine.
-                ctx.log_warning(&format!("{}", e))
+                log_warning(ctx, &format!("{}", e))
             }
         // else create new safety
         } else {
@@ -169,15 +219,63 @@ impl MCaptchaSafety {
             if let Ok(Some(_)) = MCaptcha::get_mcaptcha(&mcaptcha) {
                 let res = Self::new(ctx, duration, mcaptcha_name);
                 if res.is_err() {
-                    ctx.log_warning(&format!(
-                        "Error when creating safety timer for mcaptcha key: {}. Error: {}",
-                        mcaptcha_name,
-                        res.err().unwrap()
-                    ));
+                    log_warning(
+                        ctx,
+                        &format!(
+                            "Error when creating safety timer for mcaptcha key: {}. Error: {}",
+                            mcaptcha_name,
+                            res.err().unwrap()
+                        ),
+                    );
                 }
             }
         }
     }
+
+    /// delete safety of mcaptcha, if it has one, and stop its timer. Returns true if safety was
+    /// deleted
+    pub fn delete(ctx: &Context, mcaptcha_name: &str) -> CacheResult<bool> {
+        let safety = ctx.open_key_writable(&RedisString::create_from_slice(
+            ctx.ctx,
+            get_safety_name(mcaptcha_name).as_bytes(),
+        ));
+        if safety.key_type() == KeyType::Empty {
+            return Ok(false);
+        }
+        if let Some(timer) = safety
+            .get_value::<Self>(&MCAPTCHA_SAFETY_TYPE)?
+            .and_then(|safety_val| safety_val.timer)
+        {
+            // timer data is dropped here, so boost isn't run
+            if ctx.stop_timer::<(String, u64)>(timer).is_ok() {
+                metrics::TIMERS.decr();
+            }
+        }
+        safety.delete()?;
+        Ok(true)
+    }
+
+    /// Restore safety. Emitted during AOF rewrite. Safety's expiry is rewritten by Redis, so
+    /// when it expires, [MCaptchaSafety::on_delete] will take care of the mCaptcha like it
+    /// does when loading from RDB
+    pub fn restore(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+        let mut args = args.into_iter().skip(1);
+        let key_name = args.next_string()?;
+        let data = args.next_string()?;
+        if data != type_methods::SAFETY_RDB_VAL {
+            return Err(CacheError::new(format!("Can't restore safety from: {}", data)).into());
+        }
+
+        let key = ctx.open_key_writable(&RedisString::create_from_slice(
+            ctx.ctx,
+            key_name.as_bytes(),
+        ));
+        check_restore(ctx, &key)?;
+        key.set_value(&MCAPTCHA_SAFETY_TYPE, MCaptchaSafety::default())?;
+        metrics::SAFETIES.incr();
+        ctx.replicate_verbatim();
+        REDIS_OK
+    }
 }
 
 pub static MCAPTCHA_SAFETY_TYPE: RedisType = RedisType::new(
@@ -187,11 +285,11 @@ pub static MCAPTCHA_SAFETY_TYPE: RedisType = RedisType::new(
         version: raw::REDISMODULE_TYPE_METHOD_VERSION as u64,
         rdb_load: Some(type_methods::rdb_load),
         rdb_save: Some(type_methods::rdb_save),
-        aof_rewrite: None,
+        aof_rewrite: Some(type_methods::aof_rewrite),
         free: Some(type_methods::free),
+        mem_usage: Some(type_methods::mem_usage),
 
         // Currently unused by Redis
-        mem_usage: None,
         mem_usage2: None,
         digest: None,
 
@@ -205,48 +303,91 @@ pub static MCAPTCHA_SAFETY_TYPE: RedisType = RedisType::new(
         free_effort2: None,
         unlink: None,
         unlink2: None,
-        copy: None,
+        copy: Some(type_methods::copy),
         copy2: None,
         defrag: None,
     },
 );
 
 pub mod type_methods {
+    use std::mem::size_of;
     use std::os::raw::c_void;
+    use std::ptr;
 
     use libc::c_int;
 
-    const SAFETY_RDB_VAL: &str = "SAFETY";
+    pub const SAFETY_RDB_VAL: &str = "SAFETY";
 
     use super::*;
     #[allow(non_snake_case, unused)]
     pub extern "C" fn rdb_load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> *mut c_void {
-        let bucket = match encver {
+        let value = match load(rdb, encver) {
+            Ok(safety) => Box::into_raw(Box::new(safety)) as *mut c_void,
+            Err(e) => quarantine::on_load_error(
+                rdb,
+                &MCAPTCHA_SAFETY_TYPE,
+                "safety",
+                encver,
+                e,
+                MCaptchaSafety::default,
+            ),
+        };
+        if !value.is_null() {
+            metrics::SAFETIES.incr();
+        }
+        value
+    }
+
+    fn load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> CacheResult<MCaptchaSafety> {
+        match encver {
             0 => {
-                let data = raw::load_string(rdb).unwrap().to_string();
-                if data == SAFETY_RDB_VAL {
-                    MCaptchaSafety {}
+                let data = raw::load_string(rdb)?;
+                if data.as_slice() == SAFETY_RDB_VAL.as_bytes() {
+                    Ok(MCaptchaSafety::default())
                 } else {
-                    panic!("Can't safety from old redis RDB, data received : {}", data);
+                    Err(CacheError::new(format!(
+                        "Can't load safety, data received: {}",
+                        data.to_string_lossy()
+                    )))
                 }
             }
-            _ => panic!(
-                "Can't safety from old redis RDB, encoding version: {}",
+            _ => Err(CacheError::new(format!(
+                "Can't load safety from unknown encver: {}",
                 encver
-            ),
-        };
-
-        //        if bucket.
-        Box::into_raw(Box::new(bucket)) as *mut c_void
+            ))),
+        }
     }
 
     pub unsafe extern "C" fn free(value: *mut c_void) {
         let val = value as *mut MCaptchaSafety;
-        Box::from_raw(val);
+        drop(Box::from_raw(val));
+        metrics::SAFETIES.decr();
+    }
+
+    pub unsafe extern "C" fn mem_usage(_value: *const c_void) -> usize {
+        size_of::<MCaptchaSafety>()
+    }
+
+    /// Safeties are tied to the mcaptcha they are named after and are created when an mcaptcha
+    /// is copied, so copying them is refused
+    pub unsafe extern "C" fn copy(
+        _fromkey: *mut raw::RedisModuleString,
+        _tokey: *mut raw::RedisModuleString,
+        _value: *const c_void,
+    ) -> *mut c_void {
+        ptr::null_mut()
     }
 
     #[allow(non_snake_case, unused)]
     pub unsafe extern "C" fn rdb_save(rdb: *mut raw::RedisModuleIO, value: *mut c_void) {
         raw::save_string(rdb, SAFETY_RDB_VAL)
     }
+
+    pub unsafe extern "C" fn aof_rewrite(
+        aof: *mut raw::RedisModuleIO,
+        key: *mut raw::RedisModuleString,
+        _value: *mut c_void,
+    ) {
+        emit_aof(aof, "MCAPTCHA_CACHE.RESTORE_SAFETY", key, SAFETY_RDB_VAL)
+    }
 }
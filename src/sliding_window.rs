@@ -0,0 +1,163 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Sliding window counter, an alternative to bucket scheduled decrements. Visitors are counted in
+//! a ring of slots that covers an mCaptcha's window and expire lazily, when their slot falls out
+//! of the window. No timers or bucket keys are involved.
+use serde::{Deserialize, Serialize};
+
+/// number of slots a window is divided into
+const SLOTS: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SlidingWindow {
+    /// slot width, in milliseconds
+    slot_ms: u64,
+    /// slot, in slot widths from UNIX_EPOCH, that visitors are currently added to
+    head: u64,
+    /// visitor counts, indexed by slot modulo number of slots
+    slots: Vec<u32>,
+}
+
+impl SlidingWindow {
+    /// create sliding window for a window of `duration_ms`
+    pub fn new(duration_ms: u64) -> Self {
+        let slot_ms = (duration_ms / SLOTS).max(1);
+        // one extra slot, so that visitors are counted for at least a window
+        let len = duration_ms.div_ceil(slot_ms) + 1;
+        Self {
+            slot_ms,
+            head: 0,
+            slots: vec![0; len as usize],
+        }
+    }
+
+    /// restore sliding window from its fields
+    pub fn from_parts(slot_ms: u64, head: u64, slots: Vec<u32>) -> Self {
+        Self {
+            slot_ms,
+            head,
+            slots,
+        }
+    }
+
+    #[inline]
+    pub fn get_slot_ms(&self) -> u64 {
+        self.slot_ms
+    }
+
+    #[inline]
+    pub fn get_head(&self) -> u64 {
+        self.head
+    }
+
+    #[inline]
+    pub fn get_slots(&self) -> &[u32] {
+        &self.slots
+    }
+
+    /// slots, for moving their buffer during defragmentation. Number of slots must not change
+    #[inline]
+    pub fn get_slots_mut(&mut self) -> &mut Vec<u32> {
+        &mut self.slots
+    }
+
+    /// number of slots between head and the slot `now` falls in, capped at number of slots
+    #[inline]
+    fn elapsed(&self, now: u64) -> u64 {
+        (now / self.slot_ms)
+            .saturating_sub(self.head)
+            .min(self.slots.len() as u64)
+    }
+
+    /// visitors whose slots have fallen out of the window at `now`
+    pub fn expired(&self, now: u64) -> u32 {
+        let len = self.slots.len() as u64;
+        (1..=self.elapsed(now))
+            .map(|i| self.slots[((self.head + i) % len) as usize])
+            .fold(0, u32::saturating_add)
+    }
+
+    /// time, in milliseconds from UNIX_EPOCH, at which the oldest visitors that haven't expired
+    /// at `now` expire
+    pub fn next_expiry(&self, now: u64) -> Option<u64> {
+        let len = self.slots.len() as u64;
+        let current = now / self.slot_ms;
+        // slot n expires when slot n + len begins
+        (self.head.saturating_sub(len - 1)..=self.head)
+            .filter(|n| n + len > current)
+            .find(|n| self.slots[(n % len) as usize] > 0)
+            .map(|n| (n + len) * self.slot_ms)
+    }
+
+    /// move head to the slot `now` falls in, clearing slots that have fallen out of the window.
+    /// Returns number of visitors that expired
+    pub fn advance(&mut self, now: u64) -> u32 {
+        let len = self.slots.len() as u64;
+        let mut expired: u32 = 0;
+        for i in 1..=self.elapsed(now) {
+            let slot = &mut self.slots[((self.head + i) % len) as usize];
+            expired = expired.saturating_add(*slot);
+            *slot = 0;
+        }
+        self.head = self.head.max(now / self.slot_ms);
+        expired
+    }
+
+    /// add visitors to head. Call [SlidingWindow::advance] first
+    pub fn add(&mut self, count: u32) {
+        let len = self.slots.len() as u64;
+        let slot = &mut self.slots[(self.head % len) as usize];
+        *slot = slot.saturating_add(count);
+    }
+
+    /// estimate of heap memory used by slots
+    #[inline]
+    pub fn mem_usage(&self) -> usize {
+        self.slots.capacity() * std::mem::size_of::<u32>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_window_works() {
+        const NOW: u64 = 1_000_000;
+        let mut window = SlidingWindow::new(1000);
+        assert_eq!(window.get_slot_ms(), 100);
+        assert_eq!(window.get_slots().len(), 11);
+
+        assert_eq!(window.advance(NOW), 0);
+        window.add(5);
+        assert_eq!(window.advance(NOW + 500), 0);
+        window.add(2);
+
+        // visitors are counted for at least a window
+        assert_eq!(window.expired(NOW + 1000), 0);
+        assert_eq!(window.next_expiry(NOW + 1000), Some(NOW + 1100));
+        assert_eq!(window.next_expiry(NOW + 1100), Some(NOW + 1600));
+        assert_eq!(window.next_expiry(NOW + 1600), None);
+        assert_eq!(window.expired(NOW + 1100), 5);
+        assert_eq!(window.expired(NOW + 1600), 7);
+
+        assert_eq!(window.advance(NOW + 1100), 5);
+        assert_eq!(window.advance(NOW + 1100), 0);
+        assert_eq!(window.advance(NOW + 10_000), 2);
+        assert_eq!(window.expired(NOW + 20_000), 0);
+    }
+}
@@ -0,0 +1,231 @@
+/*
+ * Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as
+ * published by the Free Software Foundation, either version 3 of the
+ * License, or (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Verification tokens, issued after a challenge is solved and validated, once, by the site
+//! backend
+use std::time::Duration;
+
+use redis_module::native_types::RedisType;
+use redis_module::raw::KeyType;
+use redis_module::NextArg;
+use redis_module::RedisResult;
+use redis_module::RedisString;
+use redis_module::RedisValue;
+use redis_module::REDIS_OK;
+use redis_module::{raw, Context};
+
+use crate::errors::*;
+use crate::metrics;
+use crate::quarantine;
+use crate::utils::*;
+
+/// Token type version, aka encoding version
+///
+/// - 0: binary, fields are saved individually
+const MCAPTCHA_TOKEN_VERSION: i32 = 0;
+
+/// verification token, bound to the captcha(sitekey) it was issued for
+#[derive(Clone)]
+pub struct Token {
+    captcha: String,
+}
+
+impl Token {
+    pub fn new(captcha: String) -> Self {
+        Self { captcha }
+    }
+
+    /// `MCAPTCHA_CACHE.ADD_TOKEN <captcha> <token> <ttl-seconds>`
+    pub fn add_token(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+        let mut args = args.into_iter().skip(1);
+        let captcha = args.next_string()?;
+        let token = args.next_string()?;
+        let ttl = args.next_u64()?;
+        args.done()?;
+        if ttl == 0 {
+            return Err(CacheError::new(format!("Invalid token TTL: {}", ttl)).into());
+        }
+
+        let token_name = get_token_name(&captcha, &token);
+        let key = ctx.open_key_writable(&RedisString::create_from_slice(
+            ctx.ctx,
+            token_name.as_bytes(),
+        ));
+        if key.key_type() != KeyType::Empty {
+            return Err(CacheError::DuplicateToken.into());
+        }
+
+        key.set_value(&MCAPTCHA_TOKEN_TYPE, Self::new(captcha))?;
+        metrics::TOKENS.incr();
+        key.set_expire(Duration::from_secs(ttl))?;
+        ctx.replicate_verbatim();
+        REDIS_OK
+    }
+
+    /// `MCAPTCHA_CACHE.VALIDATE_TOKEN <captcha> <token>`: replies with 1 if token was issued for
+    /// captcha and 0 otherwise. Tokens are single-use, a token that is found is consumed
+    pub fn validate_token(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+        let mut args = args.into_iter().skip(1);
+        let captcha = args.next_string()?;
+        let token = args.next_string()?;
+        args.done()?;
+
+        let token_name = get_token_name(&captcha, &token);
+        let key = ctx.open_key_writable(&RedisString::create_from_slice(
+            ctx.ctx,
+            token_name.as_bytes(),
+        ));
+        let valid = match key.get_value::<Self>(&MCAPTCHA_TOKEN_TYPE)? {
+            // copies of tokens keep the captcha they were issued for
+            Some(stored) => stored.captcha == captcha,
+            None => return Ok(RedisValue::Integer(0)),
+        };
+        key.delete()?;
+        ctx.replicate_verbatim();
+        if valid {
+            metrics::TOKENS_VALIDATED.incr();
+        }
+        Ok(RedisValue::Integer(valid.into()))
+    }
+
+    /// Restore token from its serialized form. Emitted during AOF rewrite
+    pub fn restore(ctx: &Context, args: Vec<RedisString>) -> RedisResult {
+        let mut args = args.into_iter().skip(1);
+        let key_name = args.next_string()?;
+        let captcha = args.next_string()?;
+
+        let key = ctx.open_key_writable(&RedisString::create_from_slice(
+            ctx.ctx,
+            key_name.as_bytes(),
+        ));
+        check_restore(ctx, &key)?;
+        key.set_value(&MCAPTCHA_TOKEN_TYPE, Self::new(captcha))?;
+        metrics::TOKENS.incr();
+        ctx.replicate_verbatim();
+        REDIS_OK
+    }
+}
+
+pub static MCAPTCHA_TOKEN_TYPE: RedisType = RedisType::new(
+    "mcaptoken",
+    MCAPTCHA_TOKEN_VERSION,
+    raw::RedisModuleTypeMethods {
+        version: raw::REDISMODULE_TYPE_METHOD_VERSION as u64,
+        rdb_load: Some(type_methods::rdb_load),
+        rdb_save: Some(type_methods::rdb_save),
+        aof_rewrite: Some(type_methods::aof_rewrite),
+        free: Some(type_methods::free),
+        mem_usage: Some(type_methods::mem_usage),
+        digest: Some(type_methods::digest),
+
+        // Currently unused by Redis
+        mem_usage2: None,
+
+        // Aux data
+        aux_load: None,
+        aux_save: None,
+        aux_save2: None,
+        aux_save_triggers: 0,
+
+        free_effort: None,
+        free_effort2: None,
+        unlink: None,
+        unlink2: None,
+        copy: Some(type_methods::copy),
+        copy2: None,
+        defrag: None,
+    },
+);
+
+pub mod type_methods {
+    use std::mem::size_of;
+    use std::os::raw::c_void;
+
+    use libc::c_int;
+
+    use super::*;
+
+    #[allow(non_snake_case, unused)]
+    pub extern "C" fn rdb_load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> *mut c_void {
+        let value = match load(rdb, encver) {
+            Ok(token) => Box::into_raw(Box::new(token)) as *mut c_void,
+            Err(e) => {
+                quarantine::on_load_error(rdb, &MCAPTCHA_TOKEN_TYPE, "token", encver, e, || {
+                    Token::new(String::new())
+                })
+            }
+        };
+        if !value.is_null() {
+            metrics::TOKENS.incr();
+        }
+        value
+    }
+
+    fn load(rdb: *mut raw::RedisModuleIO, encver: c_int) -> CacheResult<Token> {
+        match encver {
+            0 => {
+                let captcha = raw::load_string(rdb)?.to_string_lossy();
+                Ok(Token::new(captcha))
+            }
+            _ => Err(CacheError::new(format!(
+                "Can't load token from unknown encver: {}",
+                encver
+            ))),
+        }
+    }
+
+    pub unsafe extern "C" fn free(value: *mut c_void) {
+        let val = value as *mut Token;
+        drop(Box::from_raw(val));
+        metrics::TOKENS.decr();
+    }
+
+    pub unsafe extern "C" fn mem_usage(value: *const c_void) -> usize {
+        let token = &*(value as *const Token);
+        size_of::<Token>() + token.captcha.capacity()
+    }
+
+    pub unsafe extern "C" fn digest(md: *mut raw::RedisModuleDigest, value: *mut c_void) {
+        let token = &*(value as *mut Token);
+        digest_add_str(md, &token.captcha);
+        digest_end_sequence(md);
+    }
+
+    pub unsafe extern "C" fn copy(
+        _fromkey: *mut raw::RedisModuleString,
+        _tokey: *mut raw::RedisModuleString,
+        value: *const c_void,
+    ) -> *mut c_void {
+        let token = &*(value as *const Token);
+        metrics::TOKENS.incr();
+        Box::into_raw(Box::new(token.clone())) as *mut c_void
+    }
+
+    #[allow(non_snake_case, unused)]
+    pub unsafe extern "C" fn rdb_save(rdb: *mut raw::RedisModuleIO, value: *mut c_void) {
+        let token = &*(value as *mut Token);
+        raw::save_string(rdb, &token.captcha);
+    }
+
+    pub unsafe extern "C" fn aof_rewrite(
+        aof: *mut raw::RedisModuleIO,
+        key: *mut raw::RedisModuleString,
+        value: *mut c_void,
+    ) {
+        let token = &*(value as *mut Token);
+        emit_aof(aof, "MCAPTCHA_CACHE.RESTORE_TOKEN", key, &token.captcha)
+    }
+}
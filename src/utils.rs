@@ -14,9 +14,15 @@
  * You should have received a copy of the GNU Affero General Public License
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
+use std::ffi::CString;
 use std::fmt::Display;
+use std::os::raw::{c_char, c_void};
 use std::time::{SystemTime, UNIX_EPOCH};
 
+use redis_module::key::RedisKeyWritable;
+use redis_module::raw::{self, KeyType};
+use redis_module::{Context, ContextFlags};
+
 use crate::errors::*;
 use crate::*;
 
@@ -29,7 +35,7 @@ pub fn get_bucket_name(bucket_instant: u64) -> String {
 #[inline]
 /// duration in seconds
 pub fn get_timer_name_from_bucket_name(bucket_name: &str) -> String {
-    format!("{}{}", &*PREFIX_BUCKET_TIMER, bucket_name)
+    format!("{}{}", PREFIX_BUCKET_TIMER, bucket_name)
 }
 
 #[inline]
@@ -38,17 +44,51 @@ pub fn get_bucket_name_from_timer_name(name: &str) -> Option<&str> {
     // PREFIX_BUCKET_TIMER doesn't have node unique crate::ID
     // this way, even if we are loading keys of a different instance, well
     // get BUCKET keys from whatever TIMER is expiring
-    name.strip_prefix(&*PREFIX_BUCKET_TIMER)
+    name.strip_prefix(PREFIX_BUCKET_TIMER)
+}
+
+/// `RESTORE_*` commands are internal, they are emitted by AOF rewrite and replicated by the
+/// primary. Other callers can't overwrite existing keys with them
+pub fn check_restore(ctx: &Context, key: &RedisKeyWritable) -> CacheResult<()> {
+    let internal = ctx
+        .get_flags()
+        .intersects(ContextFlags::LOADING | ContextFlags::REPLICATED);
+    if key.key_type() != KeyType::Empty && !internal {
+        return Err(CacheError::new(
+            "Key exists, RESTORE can't overwrite it".into(),
+        ));
+    }
+    Ok(())
 }
 
+/// milliseconds from UNIX_EPOCH
 #[inline]
-pub fn get_bucket_instant(duration: u64) -> CacheResult<u64> {
+pub fn now_millis() -> CacheResult<u64> {
     match SystemTime::now().duration_since(UNIX_EPOCH) {
-        Ok(val) => Ok(val.as_secs() + duration),
+        Ok(val) => Ok(val.as_millis() as u64),
         Err(_) => Err(CacheError::new("SystemTime before UNIX EPOCH!".into())),
     }
 }
 
+/// instant, in milliseconds from UNIX_EPOCH, at which bucket for a window of `duration_ms`
+/// starting at `now` runs. Windows that are a whole number of seconds are bucketed by the second,
+/// others by the millisecond. When `resolution_ms` is set, instants are rounded up to slots of
+/// `resolution_ms` so that buckets are shared across a slot
+#[inline]
+pub fn get_bucket_instant(now: u64, duration_ms: u64, resolution_ms: u64) -> u64 {
+    let granularity = if duration_ms.is_multiple_of(1000) {
+        1000
+    } else {
+        1
+    };
+    let instant = now / granularity * granularity + duration_ms;
+    if resolution_ms > 1 {
+        instant.div_ceil(resolution_ms) * resolution_ms
+    } else {
+        instant
+    }
+}
+
 #[inline]
 pub fn get_captcha_key<T: Display>(name: &T) -> String {
     format!("{}{{{}}}", &*PREFIX_CAPTCHA, name)
@@ -56,22 +96,33 @@ pub fn get_captcha_key<T: Display>(name: &T) -> String {
 
 #[inline]
 pub fn get_safety_name(mcaptcha_name: &str) -> String {
-    format!("{}{}", PREFIX_SAFETY, mcaptcha_name)
+    format!("{}{}", &*PREFIX_SAFETY, mcaptcha_name)
 }
 
 #[inline]
 pub fn get_mcaptcha_from_safety(safety_name: &str) -> Option<&str> {
-    safety_name.strip_prefix(&PREFIX_SAFETY)
+    safety_name.strip_prefix(PREFIX_SAFETY.as_str())
 }
 
 #[inline]
 pub fn is_bucket_timer(name: &str) -> bool {
-    name.contains(&*PREFIX_BUCKET_TIMER)
+    name.contains(PREFIX_BUCKET_TIMER)
 }
 
 #[inline]
 pub fn is_mcaptcha_safety(name: &str) -> bool {
-    name.contains(&PREFIX_SAFETY)
+    name.contains(PREFIX_SAFETY.as_str())
+}
+
+/// buckets created by this node or by the node that saved the RDB file this node loaded
+#[inline]
+pub fn is_bucket(name: &str) -> bool {
+    name.starts_with(&*PREFIX_BUCKET_ANY_NODE)
+}
+
+#[inline]
+pub fn is_captcha_key(name: &str) -> bool {
+    name.starts_with(&*PREFIX_CAPTCHA)
 }
 
 #[inline]
@@ -79,6 +130,103 @@ pub fn get_challenge_name(captcha: &str, challenge: &str) -> String {
     format!("{}:{{{}}}:{}", &*PREFIX_CHALLENGE, captcha, challenge)
 }
 
+/// captcha that challenge `name` was issued for
+#[inline]
+pub fn get_captcha_from_challenge_name(name: &str) -> Option<&str> {
+    name.strip_prefix(PREFIX_CHALLENGE.as_str())?
+        .strip_prefix(":{")?
+        .split_once("}:")
+        .map(|(captcha, _)| captcha)
+}
+
+#[inline]
+pub fn get_token_name(captcha: &str, token: &str) -> String {
+    format!("{}:{{{}}}:{}", &*PREFIX_TOKEN, captcha, token)
+}
+
+/// emit `cmd key payload` to AOF. Used by aof_rewrite callbacks to write commands that
+/// recreate values when AOF is replayed
+#[inline]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn emit_aof(
+    aof: *mut raw::RedisModuleIO,
+    cmd: &str,
+    key: *mut raw::RedisModuleString,
+    payload: &str,
+) {
+    let cmd = CString::new(cmd).unwrap();
+    let fmt = CString::new("sb").unwrap();
+    unsafe {
+        raw::RedisModule_EmitAOF.unwrap()(
+            aof,
+            cmd.as_ptr(),
+            fmt.as_ptr(),
+            key,
+            payload.as_ptr().cast::<c_char>(),
+            payload.len(),
+        )
+    };
+}
+
+/// add integer to the current sequence of a `DEBUG DIGEST` digest
+#[inline]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn digest_add_u64(md: *mut raw::RedisModuleDigest, val: u64) {
+    unsafe { raw::RedisModule_DigestAddLongLong.unwrap()(md, val as i64) };
+}
+
+/// add string to the current sequence of a `DEBUG DIGEST` digest
+#[inline]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn digest_add_str(md: *mut raw::RedisModuleDigest, val: &str) {
+    unsafe {
+        raw::RedisModule_DigestAddStringBuffer.unwrap()(
+            md,
+            val.as_ptr().cast::<c_char>(),
+            val.len(),
+        )
+    };
+}
+
+/// end the current sequence of a `DEBUG DIGEST` digest
+#[inline]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn digest_end_sequence(md: *mut raw::RedisModuleDigest) {
+    unsafe { raw::RedisModule_DigestEndSequence.unwrap()(md) };
+}
+
+/// move allocation to a less fragmented region. Returns null if allocation wasn't moved
+#[inline]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn defrag_alloc(ctx: *mut raw::RedisModuleDefragCtx, ptr: *mut c_void) -> *mut c_void {
+    unsafe { raw::RedisModule_DefragAlloc.unwrap()(ctx, ptr) }
+}
+
+/// move heap buffer of `vec` with [defrag_alloc]. Returns true if it was moved
+pub fn defrag_vec<T>(ctx: *mut raw::RedisModuleDefragCtx, vec: &mut Vec<T>) -> bool {
+    if vec.capacity() == 0 || std::mem::size_of::<T>() == 0 {
+        return false;
+    }
+    let moved = defrag_alloc(ctx, vec.as_mut_ptr() as *mut c_void) as *mut T;
+    if moved.is_null() {
+        return false;
+    }
+    // old buffer is freed by defrag_alloc
+    let (len, capacity) = (vec.len(), vec.capacity());
+    std::mem::forget(std::mem::replace(vec, unsafe {
+        Vec::from_raw_parts(moved, len, capacity)
+    }));
+    true
+}
+
+/// move heap buffer of `string` with [defrag_alloc]. Returns true if it was moved
+pub fn defrag_string(ctx: *mut raw::RedisModuleDefragCtx, string: &mut String) -> bool {
+    let mut buf = std::mem::take(string).into_bytes();
+    let moved = defrag_vec(ctx, &mut buf);
+    *string = unsafe { String::from_utf8_unchecked(buf) };
+    moved
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -94,4 +242,25 @@ mod tests {
             Some(bucket_name.as_str())
         );
     }
+
+    #[test]
+    fn challenge_name_works() {
+        let challenge_name = get_challenge_name("captcha", "challenge:1");
+        assert_eq!(
+            get_captcha_from_challenge_name(&challenge_name),
+            Some("captcha")
+        );
+        assert_eq!(get_captcha_from_challenge_name("captcha"), None);
+    }
+
+    #[test]
+    fn bucket_instant_works() {
+        const NOW: u64 = 1_000_123;
+        assert_eq!(get_bucket_instant(NOW, 30_000, 0), 1_030_000);
+        assert_eq!(get_bucket_instant(NOW, 250, 0), 1_000_373);
+        // rounded up to 10 second slots
+        assert_eq!(get_bucket_instant(NOW, 25_000, 10_000), 1_030_000);
+        assert_eq!(get_bucket_instant(NOW, 30_000, 10_000), 1_030_000);
+        assert_eq!(get_bucket_instant(NOW, 250, 10_000), 1_010_000);
+    }
 }
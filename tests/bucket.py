@@ -17,7 +17,11 @@ from asyncio import sleep
 import sys
 import json
 
-from mcaptcha import register
+import msgpack
+from redis.exceptions import ResponseError
+
+from mcaptcha import register, delete_captcha, captcha_exists, get_captcha_key, MCAPTCHA
+from mcaptcha import get_count, assert_count, scheduled_decrements
 import utils
 
 r = utils.connect()
@@ -25,23 +29,21 @@ utils.ping(r)
 
 COMMANDS = {
 "COUNT" : "MCAPTCHA_CACHE.ADD_VISITOR",
-"GET" : "MCAPTCHA_CACHE.GET",
+"ADD_VISITORS" : "MCAPTCHA_CACHE.ADD_VISITORS",
+"SCHEDULED_DECREMENTS" : "MCAPTCHA_CACHE.SCHEDULED_DECREMENTS",
 }
 
 def incr(key):
     data = r.execute_command(COMMANDS["COUNT"], key)
     return json.loads(data)
 
-def get_count(key):
-    try:
-        count = r.execute_command(COMMANDS["GET"], key)
-        return int(count)
-    except:
-        return 0
+def incr_msgpack(key):
+    data = r.execute_command(COMMANDS["COUNT"], key, "FORMAT", "msgpack")
+    return msgpack.unpackb(data)
 
-def assert_count(expect, key):
-    count = get_count(key)
-    assert count == expect
+def incr_by(key, count):
+    data = r.execute_command(COMMANDS["ADD_VISITORS"], key, count)
+    return json.loads(data)
 
 async def incr_one_works():
     try:
@@ -98,3 +100,254 @@ async def difficulty_works():
         print("[*] Difficulty factor works")
     except Exception as e:
         raise e
+
+
+async def incr_msgpack_works():
+    key = "incr_msgpack"
+    try:
+        register(key)
+        data = incr_msgpack(key)
+        assert data["difficulty_factor"] == 50
+        assert data["duration"] == 5
+        print("[*] Incr with msgpack response works")
+    except Exception as e:
+        raise e
+
+
+async def add_visitors_works():
+    key = "add_visitors_works"
+    try:
+        register(key)
+        initial_count = get_count(key)
+        data = incr_by(key, 51)
+        assert data["difficulty_factor"] == 500
+        assert_count(initial_count + 51, key)
+        # batches are capped
+        try:
+            incr_by(key, 1_000_001)
+            assert False
+        except ResponseError:
+            pass
+        assert_count(initial_count + 51, key)
+        # wait till expiry
+        await sleep(5 + 2)
+        assert_count(initial_count, key)
+        print("[*] Add visitors works")
+    except Exception as e:
+        raise e
+
+
+async def scheduled_decrements_works():
+    key = "scheduled_decrements_works"
+    try:
+        register(key)
+        incr_by(key, 10)
+        incr(key)
+        assert scheduled_decrements(key) == 11
+        # wait till expiry
+        await sleep(5 + 2)
+        assert scheduled_decrements(key) == 0
+        print("[*] Scheduled decrements works")
+    except Exception as e:
+        raise e
+
+
+async def decrement_survives_reload_works():
+    key = "decrement_survives_reload_works"
+    rearmed_key = "decrement_survives_reload_works_rearmed"
+    try:
+        register(key)
+        if captcha_exists(rearmed_key):
+            delete_captcha(rearmed_key)
+        payload = dict(MCAPTCHA, duration=3)
+        r.execute_command("MCAPTCHA_CACHE.ADD_CAPTCHA", rearmed_key, json.dumps(payload))
+        initial_count = get_count(key)
+        incr_by(key, 5)
+        incr_by(rearmed_key, 3)
+
+        # bucket under a name that has no timer, like buckets loaded after a restart, is only
+        # run once its timer is re-armed
+        [[instant, _count]] = r.execute_command(COMMANDS["SCHEDULED_DECREMENTS"], rearmed_key)
+        [bucket] = [b for b in r.keys(f"mcap:bucket:*:{instant}") if b"{reload}" not in b]
+        r.rename(bucket, f"mcap:bucket:{{reload}}:{instant}")
+
+        timers = metrics()["mcaptcha_cache_timers"]
+        r.execute_command("DEBUG", "RELOAD")
+        # pending timers outlive reload, only the bucket without one gets a new timer
+        assert metrics()["mcaptcha_cache_timers"] <= timers + 1
+        assert_count(initial_count + 5, key)
+        assert_count(3, rearmed_key)
+        # wait till expiry
+        await sleep(5 + 2)
+        assert_count(initial_count, key)
+        assert_count(0, rearmed_key)
+        print("[*] Decrement survives reload works")
+    except Exception as e:
+        raise e
+
+
+async def aof_rewrite_done():
+    while r.info("persistence")["aof_rewrite_in_progress"] == 1 \
+            or r.info("persistence")["aof_rewrite_scheduled"] == 1:
+        await sleep(0.1)
+
+
+async def aof_works():
+    key = "aof_works"
+    appendonly = r.config_get("appendonly")["appendonly"]
+    try:
+        if captcha_exists(key):
+            delete_captcha(key)
+        r.config_set("appendonly", "yes")
+        await aof_rewrite_done()
+        payload = dict(MCAPTCHA, duration=30)
+        r.execute_command("MCAPTCHA_CACHE.ADD_CAPTCHA", key, json.dumps(payload))
+        incr_by(key, 3)
+
+        # rewritten AOF holds state as RESTORE_* commands
+        r.bgrewriteaof()
+        await aof_rewrite_done()
+        # writes after rewrite are propagated to the AOF as their effects, RESTORE_* commands too
+        incr_by(key, 2)
+
+        r.execute_command("DEBUG", "LOADAOF")
+        assert captcha_exists(key)
+        assert_count(5, key)
+        assert scheduled_decrements(key) == 5
+        print("[*] AOF works")
+    except Exception as e:
+        raise e
+    finally:
+        r.config_set("appendonly", appendonly)
+
+
+async def fractional_duration_works():
+    key = "fractional_duration_works"
+    try:
+        if captcha_exists(key):
+            delete_captcha(key)
+        payload = dict(MCAPTCHA, duration=0.5)
+        r.execute_command("MCAPTCHA_CACHE.ADD_CAPTCHA", key, json.dumps(payload))
+        data = incr(key)
+        assert data["duration"] == 1
+        assert_count(1, key)
+        # wait till expiry
+        await sleep(0.5 + 0.5)
+        assert_count(0, key)
+        print("[*] Fractional duration works")
+    except Exception as e:
+        raise e
+
+
+async def sliding_window_works():
+    key = "sliding_window_works"
+    try:
+        if captcha_exists(key):
+            delete_captcha(key)
+        payload = dict(MCAPTCHA, duration=1, mode="sliding_window")
+        r.execute_command("MCAPTCHA_CACHE.ADD_CAPTCHA", key, json.dumps(payload))
+        for _ in range(51):
+            data = incr(key)
+        assert data["difficulty_factor"] == 500
+        assert_count(51, key)
+        assert scheduled_decrements(key) == 0
+        assert r.exists(f"safety:mcap:captcha::{{{key}}}") == 0
+        # wait till expiry
+        await sleep(1 + 1)
+        assert_count(0, key)
+        print("[*] Sliding window works")
+    except Exception as e:
+        raise e
+
+
+async def defrag_works():
+    key_prefix = "defrag_works"
+    try:
+        try:
+            r.config_set("activedefrag", "yes")
+        except Exception:
+            print("[*] Active defragmentation unavailable, skipping defrag test")
+            return
+        r.config_set("active-defrag-ignore-bytes", "1")
+        r.config_set("active-defrag-threshold-lower", "1")
+
+        hits = r.info("stats")["active_defrag_key_hits"]
+        keys = [f"{key_prefix}_{i}" for i in range(50)]
+        for key in keys:
+            register(key)
+
+        # churn: grow and drain bucket decrement maps
+        for _ in range(3):
+            for key in keys:
+                for _ in range(20):
+                    incr(key)
+            await sleep(5 + 2)
+
+        for key in keys:
+            assert_count(0, key)
+        assert r.info("stats")["active_defrag_key_hits"] > hits
+        utils.ping(r)
+        print("[*] Defrag works")
+    except Exception as e:
+        raise e
+    finally:
+        r.config_set("activedefrag", "no")
+
+
+def metrics():
+    return r.info("mcaptcha_cache_metrics")
+
+
+async def metrics_works():
+    key = "metrics_works"
+    try:
+        register(key)
+        before = metrics()
+        assert before["mcaptcha_cache_mcaptchas"] >= 1
+        incr_by(key, 3)
+        after = metrics()
+        assert after["mcaptcha_cache_visitors_added"] >= before["mcaptcha_cache_visitors_added"] + 3
+        assert after["mcaptcha_cache_buckets"] >= 1
+        assert after["mcaptcha_cache_timers"] >= 1
+        # wait till expiry
+        await sleep(5 + 2)
+        assert metrics()["mcaptcha_cache_decrements_executed"] > before["mcaptcha_cache_decrements_executed"]
+        print("[*] Metrics works")
+    except Exception as e:
+        raise e
+
+
+def digest(key):
+    [value] = r.execute_command("DEBUG", "DIGEST-VALUE", key)
+    return value
+
+
+async def digest_works():
+    key = "digest_works"
+    twin_key = "digest_works_twin"
+    try:
+        register(key)
+        register(twin_key)
+        captcha, twin = get_captcha_key(key), get_captcha_key(twin_key)
+        # identical mCaptchas digest equal
+        assert digest(captcha) == digest(twin)
+
+        incr_by(key, 3)
+        assert digest(captcha) != digest(twin)
+        incr_by(twin_key, 3)
+        assert digest(captcha) == digest(twin)
+
+        # bucket digest covers its decrements, visitors may land in the next bucket
+        [[instant, _count]] = r.execute_command(COMMANDS["SCHEDULED_DECREMENTS"], key)
+        [bucket] = r.keys(f"mcap:bucket:*:{instant}")
+        before = digest(bucket)
+        incr_by(key, 1)
+        if r.execute_command(COMMANDS["SCHEDULED_DECREMENTS"], key)[0][1] == 4:
+            assert digest(bucket) != before
+        # wait till expiry
+        await sleep(5 + 2)
+        print("[*] Digest works")
+    except Exception as e:
+        raise e
+
+
@@ -15,7 +15,9 @@
 # You should have received a copy of the GNU Affero General Public License
 # along with this program.  If not, see <https://www.gnu.org/licenses/>.
 from asyncio import sleep
+from hashlib import sha256
 import json
+import struct
 
 import redis
 
@@ -34,11 +36,19 @@ utils.ping(r)
 COMMANDS = {
  "ADD" :"MCAPTCHA_CACHE.ADD_CHALLENGE",
  "GET" :"MCAPTCHA_CACHE.GET_CHALLENGE",
- "DEL" :"MCAPTCHA_CACHE.DELETE_CHALLENGE"
+ "DEL" :"MCAPTCHA_CACHE.DELETE_CHALLENGE",
+ "VERIFY" :"MCAPTCHA_CACHE.VERIFY_CHALLENGE",
+ "VERIFY_POW" :"MCAPTCHA_CACHE.VERIFY_POW",
+ "ADD_TOKEN" :"MCAPTCHA_CACHE.ADD_TOKEN",
+ "VALIDATE_TOKEN" :"MCAPTCHA_CACHE.VALIDATE_TOKEN"
 }
 
+POW_SALT = "verify_pow_works_salt"
+U128_MAX = 2**128 - 1
+
 CHALLENGE_NOT_FOUND = "Challenge not found"
 DUPLICATE_CHALLENGE = "Challenge already exists"
+DUPLICATE_TOKEN = "Token already exists"
 REDIS_OK = bytes("OK", 'utf-8')
 
 def add_challenge(captcha, challenge):
@@ -64,6 +74,33 @@ def delete_challenge(captcha, challenge):
     except Exception as e:
         return e
 
+def verify_challenge(captcha, challenge, difficulty):
+    """Verify challenge and return verdict"""
+    verdict = r.execute_command(COMMANDS["VERIFY"], captcha, challenge, difficulty)
+    if isinstance(verdict, list):
+        verdict = dict(zip(verdict[0::2], verdict[1::2]))
+    return verdict
+
+def verify_pow(captcha, challenge, nonce, result):
+    """Verify PoW and return verdict"""
+    verdict = r.execute_command(COMMANDS["VERIFY_POW"], captcha, challenge, nonce, result)
+    if isinstance(verdict, list):
+        verdict = dict(zip(verdict[0::2], verdict[1::2]))
+    return verdict
+
+
+def prove_work(salt, challenge, accept):
+    """Find nonce whose score is accepted, like mcaptcha_pow_sha256 does.
+    Challenge string is bincode serialized: length as little endian u64 followed by bytes"""
+    prefix = salt.encode() + struct.pack("<Q", len(challenge)) + challenge.encode()
+    nonce = 0
+    while True:
+        nonce += 1
+        digest = sha256(prefix + str(nonce).encode()).digest()
+        score = int.from_bytes(digest[:16], "big")
+        if accept(score):
+            return (nonce, str(score))
+
 
 def get_challenge(challenge):
     """Get challenge JSON"""
@@ -157,3 +194,137 @@ async def delete_challenge_works():
         print("[*] Delete Challenge works")
     except Exception as e:
         raise e
+
+
+async def verify_challenge_works():
+    """Test: Verify Challenges"""
+    try:
+        challenge_name = "verify_challenge"
+        key = "verify_challenge_key"
+        challenge = get_challenge(challenge_name)
+
+        add_challenge(key, challenge)
+        verdict = verify_challenge(key, challenge_name, 500)
+        assert verdict[b"valid"] == 1
+        assert verdict[b"reason"] == b"ok"
+        assert verdict[b"difficulty_factor"] == 500
+        assert verdict[b"duration"] == 5
+
+        # replay
+        verdict = verify_challenge(key, challenge_name, 500)
+        assert verdict[b"valid"] == 0
+        assert verdict[b"reason"] == b"not_found"
+        assert verdict[b"difficulty_factor"] is None
+
+        # downgrade consumes challenge
+        add_challenge(key, challenge)
+        verdict = verify_challenge(key, challenge_name, 501)
+        assert verdict[b"valid"] == 0
+        assert verdict[b"reason"] == b"difficulty_too_low"
+        assert str(get_challenge_from_redis(key, challenge_name)) == CHALLENGE_NOT_FOUND
+
+        # challenge is bound to captcha
+        add_challenge(key, challenge)
+        verdict = verify_challenge("other_" + key, challenge_name, 500)
+        assert verdict[b"reason"] == b"not_found"
+        delete_challenge(key, challenge_name)
+
+        print("[*] Verify Challenge works")
+    except Exception as e:
+        raise e
+
+
+async def verify_pow_works():
+    """Test: Verify PoW"""
+    name = "mcaptcha_cache.pow-salt"
+    r.config_set(name, POW_SALT)
+    try:
+        # salt is a secret, it isn't listed by module's CONFIG GET
+        res = r.execute_command("MCAPTCHA_CACHE.CONFIG", "GET", "pow-salt")
+        assert res == []
+        res = r.execute_command("MCAPTCHA_CACHE.CONFIG", "GET", "*")
+        assert b"pow-salt" not in res and POW_SALT.encode() not in res
+        challenge_name = "verify_pow"
+        key = "verify_pow_key"
+        challenge = get_challenge(challenge_name)
+        target = U128_MAX - U128_MAX // 500
+
+        add_challenge(key, challenge)
+        (nonce, result) = prove_work(POW_SALT, challenge_name, lambda score: score >= target)
+
+        # failed verification doesn't consume challenge
+        verdict = verify_pow(key, challenge_name, nonce, str(int(result) - 1))
+        assert verdict[b"reason"] == b"invalid_proof"
+        (weak_nonce, weak_result) = prove_work(POW_SALT, challenge_name, lambda score: score < target)
+        verdict = verify_pow(key, challenge_name, weak_nonce, weak_result)
+        assert verdict[b"reason"] == b"insufficient_work"
+
+        verdict = verify_pow(key, challenge_name, nonce, result)
+        assert verdict[b"valid"] == 1
+        assert verdict[b"reason"] == b"ok"
+        assert verdict[b"difficulty_factor"] == 500
+
+        # replay
+        verdict = verify_pow(key, challenge_name, nonce, result)
+        assert verdict[b"reason"] == b"not_found"
+
+        print("[*] Verify PoW works")
+    except Exception as e:
+        raise e
+    finally:
+        r.config_set(name, "")
+
+
+def add_token(captcha, token, ttl):
+    """Add verification token to Redis"""
+    try:
+        return r.execute_command(COMMANDS["ADD_TOKEN"], captcha, token, ttl)
+    except Exception as e:
+        return e
+
+
+def validate_token(captcha, token):
+    """Validate verification token"""
+    return r.execute_command(COMMANDS["VALIDATE_TOKEN"], captcha, token)
+
+
+async def token_works():
+    """Test: Verification Tokens"""
+    try:
+        key = "token_key"
+        token = "token_works"
+
+        assert add_token(key, token, 5) == REDIS_OK
+        assert str(add_token(key, token, 5)) == DUPLICATE_TOKEN
+        # token is bound to captcha
+        assert validate_token("other_" + key, token) == 0
+        assert validate_token(key, token) == 1
+        # single-use
+        assert validate_token(key, token) == 0
+
+        add_token(key, token, 1)
+        await sleep(1 + 1)
+        assert validate_token(key, token) == 0
+        print("[*] Token works")
+    except Exception as e:
+        raise e
+
+
+async def restore_refuses_overwrite_works():
+    """Test: RESTORE_* commands don't overwrite keys of clients"""
+    try:
+        key = "restore_refuses_overwrite_works"
+        token = "restore_refuses_overwrite_works"
+        token_key = f"mcap:TOKEN:{{{key}}}:{token}"
+        r.delete(token_key)
+
+        assert r.execute_command("MCAPTCHA_CACHE.RESTORE_TOKEN", token_key, key) == REDIS_OK
+        try:
+            r.execute_command("MCAPTCHA_CACHE.RESTORE_TOKEN", token_key, "other_" + key)
+            assert False
+        except redis.exceptions.ResponseError:
+            pass
+        assert validate_token(key, token) == 1
+        print("[*] RESTORE refuses overwrite works")
+    except Exception as e:
+        raise e
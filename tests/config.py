@@ -0,0 +1,64 @@
+#!/bin/env /usr/bin/python3
+#
+# Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+# 
+# This program is free software: you can redistribute it and/or modify
+# it under the terms of the GNU Affero General Public License as
+# published by the Free Software Foundation, either version 3 of the
+# License, or (at your option) any later version.
+# 
+# This program is distributed in the hope that it will be useful,
+# but WITHOUT ANY WARRANTY; without even the implied warranty of
+# MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+# GNU Affero General Public License for more details.
+# 
+# You should have received a copy of the GNU Affero General Public License
+# along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+from redis.exceptions import ResponseError
+
+import utils
+
+r = utils.connect()
+utils.ping(r)
+
+COMMANDS = {
+    "CONFIG": "MCAPTCHA_CACHE.CONFIG",
+}
+
+
+def config_get(name):
+    res = r.execute_command(COMMANDS["CONFIG"], "GET", name)
+    return dict(zip(res[0::2], res[1::2]))
+
+
+async def config_get_works():
+    config = config_get("*")
+    assert config[b"key-prefix"] == b"mcap"
+    assert config[b"safety-prefix"] == b"safety:"
+    assert config[b"rdb-load-policy"] == b"fail"
+    assert config[b"safety-expiry-multiplier"] == b"2"
+    assert config[b"log-level"] == b"debug"
+    assert config_get("bucket-expiry-offset") == {b"bucket-expiry-offset": b"30"}
+    assert config_get("unknown") == {}
+    print("[*] Config get works")
+
+
+async def config_set_works():
+    name = "mcaptcha_cache.bucket-expiry-offset"
+    r.config_set(name, 45)
+    try:
+        assert r.config_get(name) == {name: "45"}
+        assert config_get("bucket-expiry-offset") == {b"bucket-expiry-offset": b"45"}
+    finally:
+        r.config_set(name, 30)
+
+    # key-prefix can only be set when module is loaded
+    try:
+        r.config_set("mcaptcha_cache.key-prefix", "tenantA")
+        immutable = False
+    except ResponseError:
+        immutable = True
+    assert immutable
+    assert config_get("key-prefix") == {b"key-prefix": b"mcap"}
+    print("[*] Config set works")
@@ -15,8 +15,12 @@
 # You should have received a copy of the GNU Affero General Public License
 # along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+from asyncio import sleep
 import json
 
+import msgpack
+from redis.exceptions import ResponseError
+
 import utils
 
 r = utils.connect()
@@ -35,6 +39,9 @@ COMMANDS = {
     "DELETE_CAPTCHA": "MCAPTCHA_CACHE.DELETE_CAPTCHA",
     "CAPTCHA_EXISTS": "MCAPTCHA_CACHE.CAPTCHA_EXISTS",
     "RENAME_CAPTCHA": "MCAPTCHA_CACHE.RENAME_CAPTCHA",
+    "GET": "MCAPTCHA_CACHE.GET",
+    "ADD_VISITORS": "MCAPTCHA_CACHE.ADD_VISITORS",
+    "SCHEDULED_DECREMENTS": "MCAPTCHA_CACHE.SCHEDULED_DECREMENTS",
 }
 
 payload = json.dumps(MCAPTCHA)
@@ -46,6 +53,12 @@ def delete_captcha(key):
 def add_captcha(key):
     r.execute_command(COMMANDS["ADD_CAPTCHA"], key, payload)
 
+def add_captcha_msgpack(key):
+    r.execute_command(COMMANDS["ADD_CAPTCHA"], key, msgpack.packb(MCAPTCHA), "FORMAT", "msgpack")
+
+def get_captcha_key(key):
+    return f"mcap:captcha::{{{key}}}"
+
 def rename_captcha(key, new_key):
     r.execute_command(COMMANDS["RENAME_CAPTCHA"], key, new_key)
 
@@ -65,6 +78,25 @@ def register(key):
 
     add_captcha(key)
 
+def get_count(key):
+    try:
+        count = r.execute_command(COMMANDS["GET"], key)
+        return int(count)
+    except:
+        return 0
+
+def assert_count(expect, key):
+    count = get_count(key)
+    assert count == expect
+
+def add_visitors(key, count):
+    data = r.execute_command(COMMANDS["ADD_VISITORS"], key, count)
+    return json.loads(data)
+
+def scheduled_decrements(key):
+    scheduled = r.execute_command(COMMANDS["SCHEDULED_DECREMENTS"], key)
+    return sum(count for (_instant, count) in scheduled)
+
 async def captcha_exists_works():
     key = "captcha_delete_works"
     if captcha_exists(key):
@@ -94,9 +126,209 @@ async def rename_captcha_works():
     key = "rename_captcha_works"
     new_key = "new_key_rename_captcha_works"
     register(key)
+    if captcha_exists(new_key):
+        delete_captcha(new_key)
     exists = captcha_exists(key)
     assert exists is True
     rename_captcha(key, new_key)
     assert captcha_exists(key) is False
     assert captcha_exists(new_key) is True
     print("[*] Rename captcha works")
+
+
+async def register_captcha_msgpack_works():
+    key = "register_captcha_msgpack_works"
+    if captcha_exists(key):
+        delete_captcha(key)
+    add_captcha_msgpack(key)
+    assert captcha_exists(key) is True
+    print("[*] Add captcha with msgpack payload works")
+
+
+async def memory_usage_works():
+    key = "memory_usage_works"
+    big_key = "memory_usage_works_big"
+    register(key)
+    if captcha_exists(big_key):
+        delete_captcha(big_key)
+    levels = [{"visitor_threshold": 50 * i, "difficulty_factor": 50 * i} for i in range(1, 101)]
+    big = json.dumps({"levels": levels, "duration": 5})
+    r.execute_command(COMMANDS["ADD_CAPTCHA"], big_key, big)
+
+    usage = r.memory_usage(get_captcha_key(key))
+    big_usage = r.memory_usage(get_captcha_key(big_key))
+    assert big_usage > usage
+    print("[*] Memory usage works")
+
+
+async def copy_captcha_works():
+    key = "copy_captcha_works"
+    new_key = "new_key_copy_captcha_works"
+    register(key)
+    if captcha_exists(new_key):
+        delete_captcha(new_key)
+    assert r.copy(get_captcha_key(key), get_captcha_key(new_key)) is True
+    assert captcha_exists(key) is True
+    assert captcha_exists(new_key) is True
+    assert r.exists(f"safety:{get_captcha_key(new_key)}") == 1
+
+    # pending decrements aren't copied
+    delete_captcha(new_key)
+    add_visitors(key, 3)
+    assert r.copy(get_captcha_key(key), get_captcha_key(new_key)) is True
+    assert get_state(key)[b"seconds_until_decrement"] is not None
+    assert get_state(new_key)[b"seconds_until_decrement"] is None
+    assert scheduled_decrements(new_key) == 0
+    print("[*] Copy captcha works")
+
+
+def stats(key):
+    return json.loads(r.execute_command("MCAPTCHA_CACHE.STATS", key))
+
+
+async def stats_works():
+    key = "stats_works"
+    try:
+        register(key)
+        assert stats(key)["last_visit"] is None
+
+        add_visitors(key, 51)
+        challenge = json.dumps({"difficulty": 500, "duration": 5, "challenge": key})
+        r.execute_command("MCAPTCHA_CACHE.ADD_CHALLENGE", key, challenge)
+        r.execute_command("MCAPTCHA_CACHE.GET_CHALLENGE", key, key)
+
+        data = stats(key)
+        assert data["visitors"] == 51
+        assert data["difficulty_factor"] == 500
+        assert data["duration"] == MCAPTCHA["duration"]
+        assert data["levels"] == MCAPTCHA["levels"]
+        assert data["peak_visitors"] == 51
+        assert data["total_visitors"] == 51
+        assert data["challenges_issued"] == 1
+        assert data["challenges_verified"] == 1
+        assert data["last_visit"] is not None
+        print("[*] Stats works")
+    except Exception as e:
+        raise e
+
+
+def get_state(key):
+    state = r.execute_command("MCAPTCHA_CACHE.GET_STATE", key)
+    if isinstance(state, list):
+        state = dict(zip(state[0::2], state[1::2]))
+    return state
+
+
+async def get_state_works():
+    key = "get_state_works"
+    try:
+        register(key)
+        state = get_state(key)
+        assert state[b"visitors"] == 0
+        assert state[b"level"] == 0
+        assert state[b"next_threshold"] == 50
+        assert state[b"seconds_until_decrement"] is None
+
+        add_visitors(key, 51)
+        state = get_state(key)
+        assert state[b"visitors"] == 51
+        assert state[b"difficulty_factor"] == 500
+        assert state[b"level"] == 1
+        assert state[b"next_threshold"] is None
+        assert 0 < state[b"seconds_until_decrement"] <= MCAPTCHA["duration"]
+        print("[*] Get state works")
+    except Exception as e:
+        raise e
+
+
+async def update_captcha_works():
+    key = "update_captcha_works"
+    try:
+        register(key)
+        add_visitors(key, 51)
+        assert get_state(key)[b"difficulty_factor"] == 500
+
+        levels = [{"visitor_threshold": 100, "difficulty_factor": 10}]
+        update = json.dumps({"levels": levels})
+        r.execute_command("MCAPTCHA_CACHE.UPDATE_CAPTCHA", key, update)
+        state = get_state(key)
+        assert state[b"visitors"] == 51
+        assert state[b"difficulty_factor"] == 10
+        assert scheduled_decrements(key) == 51
+        assert stats(key)["duration"] == MCAPTCHA["duration"]
+
+        # visitors are still decremented
+        await sleep(MCAPTCHA["duration"] + 2)
+        assert_count(0, key)
+        print("[*] Update captcha works")
+    except Exception as e:
+        raise e
+
+
+async def rename_captcha_keeps_state_works():
+    key = "rename_captcha_keeps_state_works"
+    new_key = "new_rename_captcha_keeps_state_works"
+    try:
+        register(key)
+        if captcha_exists(new_key):
+            delete_captcha(new_key)
+        add_visitors(key, 51)
+        r.execute_command("MCAPTCHA_CACHE.RENAME_CAPTCHA", key, new_key)
+        assert captcha_exists(key) is False
+        assert_count(51, new_key)
+        assert scheduled_decrements(new_key) == 51
+        assert scheduled_decrements(key) == 0
+        assert stats(new_key)["total_visitors"] == 51
+
+        # renaming a missing captcha or onto an existing one fails
+        register(key)
+        for (src, dst) in [(key, new_key), ("missing_" + key, key)]:
+            try:
+                r.execute_command("MCAPTCHA_CACHE.RENAME_CAPTCHA", src, dst)
+                assert False
+            except ResponseError:
+                pass
+
+        # pending decrements follow the counter
+        await sleep(MCAPTCHA["duration"] + 2)
+        assert_count(0, new_key)
+        print("[*] Rename captcha keeps state works")
+    except Exception as e:
+        raise e
+
+
+def delete_captcha_with_summary(key, *args):
+    summary = r.execute_command("MCAPTCHA_CACHE.DELETE_CAPTCHA", key, *args)
+    if isinstance(summary, list):
+        summary = dict(zip(summary[0::2], summary[1::2]))
+    return summary
+
+
+async def delete_captcha_cleanup_works():
+    key = "delete_captcha_cleanup_works"
+    try:
+        register(key)
+        add_visitors(key, 10)
+        summary = delete_captcha_with_summary(key)
+        assert summary[b"safety"] == 1
+        assert summary[b"pending_decrements"] == 10
+        assert summary[b"challenges"] is None
+        assert scheduled_decrements(key) == 0
+        assert r.exists(f"safety:{get_captcha_key(key)}") == 0
+
+        register(key)
+        for challenge in ["first", "second", "third"]:
+            payload = json.dumps({"difficulty": 500, "duration": 5, "challenge": challenge})
+            r.execute_command("MCAPTCHA_CACHE.ADD_CHALLENGE", key, payload)
+        # consumed challenges aren't counted
+        r.execute_command("MCAPTCHA_CACHE.GET_CHALLENGE", key, "third")
+        summary = delete_captcha_with_summary(key, "CHALLENGES")
+        assert summary[b"challenges"] == 2
+        try:
+            r.execute_command("MCAPTCHA_CACHE.GET_CHALLENGE", key, "first")
+            assert False
+        except ResponseError:
+            pass
+        print("[*] Delete captcha cleanup works")
+    except Exception as e:
+        raise e
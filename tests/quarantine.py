@@ -0,0 +1,146 @@
+#!/bin/env /usr/bin/python3
+#
+# Copyright (C) 2021  Aravinth Manivannan <realaravinth@batsense.net>
+#
+# This program is free software: you can redistribute it and/or modify
+# it under the terms of the GNU Affero General Public License as
+# published by the Free Software Foundation, either version 3 of the
+# License, or (at your option) any later version.
+#
+# This program is distributed in the hope that it will be useful,
+# but WITHOUT ANY WARRANTY; without even the implied warranty of
+# MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+# GNU Affero General Public License for more details.
+#
+# You should have received a copy of the GNU Affero General Public License
+# along with this program.  If not, see <https://www.gnu.org/licenses/>.
+import json
+
+from redis.exceptions import ResponseError
+
+from mcaptcha import captcha_exists, delete_captcha, get_captcha_key, MCAPTCHA
+import utils
+
+r = utils.connect()
+utils.ping(r)
+
+COMMANDS = {
+    "QUARANTINE": "MCAPTCHA_CACHE.QUARANTINE",
+}
+
+POLICY = "mcaptcha_cache.rdb-load-policy"
+
+# module value opcodes, see RDB_MODULE_OPCODE_* in Redis
+OPCODE_EOF = 0
+OPCODE_SINT = 1
+OPCODE_UINT = 2
+OPCODE_FLOAT = 3
+OPCODE_DOUBLE = 4
+
+
+def crc64(data):
+    """CRC-64/Jones, used by Redis to checksum DUMP payloads"""
+    crc = 0
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            crc = (crc >> 1) ^ (0x95AC9329AC4BC9B5 if crc & 1 else 0)
+    return crc
+
+
+def read_len(buf, i):
+    """read length saved by rdbSaveLen. Returns length and index past it"""
+    b = buf[i]
+    if b >> 6 == 0:
+        return b & 0x3F, i + 1
+    if b >> 6 == 1:
+        return ((b & 0x3F) << 8) | buf[i + 1], i + 2
+    if b == 0x80:
+        return int.from_bytes(buf[i + 1:i + 5], "big"), i + 5
+    if b == 0x81:
+        return int.from_bytes(buf[i + 1:i + 9], "big"), i + 9
+    raise ValueError(f"Unsupported length encoding: {b}")
+
+
+def module_fields(payload):
+    """split DUMP payload of a module value into header, fields and trailer. Payload must be
+    dumped with rdbcompression disabled"""
+    body = payload[:-8]
+    # value type, followed by module ID
+    _, start = read_len(body, 1)
+    fields = []
+    i = start
+    while True:
+        opcode, j = read_len(body, i)
+        if opcode == OPCODE_EOF:
+            break
+        if opcode in (OPCODE_SINT, OPCODE_UINT):
+            _, j = read_len(body, j)
+        elif opcode == OPCODE_FLOAT:
+            j += 4
+        elif opcode == OPCODE_DOUBLE:
+            j += 8
+        elif body[j] >> 6 == 3:
+            # integer encoded string
+            j += 1 + {0: 1, 1: 2, 2: 4}[body[j] & 0x3F]
+        else:
+            n, j = read_len(body, j)
+            j += n
+        fields.append(body[i:j])
+        i = j
+    return body[:start], fields, body[i:]
+
+
+def module_payload(header, fields, trailer):
+    body = header + b"".join(fields) + trailer
+    return body + crc64(body).to_bytes(8, "little")
+
+
+def dump(key):
+    compression = r.config_get("rdbcompression")["rdbcompression"]
+    r.config_set("rdbcompression", "no")
+    try:
+        return r.dump(key)
+    finally:
+        r.config_set("rdbcompression", compression)
+
+
+def quarantine():
+    return r.execute_command(COMMANDS["QUARANTINE"])
+
+
+async def quarantine_works():
+    key = "quarantine_works"
+    corrupt_key = "quarantine_works_corrupt"
+    try:
+        if captcha_exists(key):
+            delete_captcha(key)
+        r.delete(corrupt_key)
+        payload = dict(MCAPTCHA, mode="sliding_window")
+        r.execute_command("MCAPTCHA_CACHE.ADD_CAPTCHA", key, json.dumps(payload))
+
+        # sliding window with 0 ms slots, read before the rest of the value
+        header, fields, trailer = module_fields(dump(get_captcha_key(key)))
+        fields[3] = bytes([OPCODE_UINT, 0])
+        corrupt = module_payload(header, fields, trailer)
+
+        for policy in ["fail", "quarantine"]:
+            r.config_set(POLICY, policy)
+            # values are only quarantined while loading, RESTORE of a corrupt value always fails
+            try:
+                r.restore(corrupt_key, 0, corrupt)
+                assert False
+            except ResponseError:
+                pass
+            assert r.exists(corrupt_key) == 0
+            assert [val for val in quarantine() if val[0] == corrupt_key.encode()] == []
+
+        r.execute_command("DEBUG", "RELOAD")
+        assert captcha_exists(key)
+        r.execute_command(COMMANDS["QUARANTINE"], "CLEAR")
+        assert quarantine() == []
+        print("[*] Quarantine works")
+    except Exception as e:
+        raise e
+    finally:
+        r.config_set(POLICY, "fail")
@@ -19,6 +19,8 @@ import asyncio
 import bucket
 import mcaptcha
 import challenge
+import config
+import quarantine
 
 
 class Runner(object):
@@ -26,15 +28,44 @@ class Runner(object):
         bucket.incr_one_works,
         bucket.race_works,
         bucket.difficulty_works,
+        bucket.incr_msgpack_works,
+        bucket.add_visitors_works,
+        bucket.scheduled_decrements_works,
+        bucket.fractional_duration_works,
+        bucket.sliding_window_works,
+        bucket.metrics_works,
+        bucket.digest_works,
         mcaptcha.delete_captcha_works,
         mcaptcha.captcha_exists_works,
         mcaptcha.register_captcha_works,
         mcaptcha.rename_captcha_works,
+        mcaptcha.register_captcha_msgpack_works,
+        mcaptcha.memory_usage_works,
+        mcaptcha.copy_captcha_works,
+        mcaptcha.stats_works,
+        mcaptcha.get_state_works,
+        mcaptcha.update_captcha_works,
+        mcaptcha.rename_captcha_keeps_state_works,
+        mcaptcha.delete_captcha_cleanup_works,
         challenge.add_challenge_works,
         challenge.challenge_doesnt_exist,
         challenge.challenge_ttl_works,
         challenge.duplicate_challenge_works,
         challenge.delete_challenge_works,
+        challenge.verify_challenge_works,
+        challenge.verify_pow_works,
+        challenge.token_works,
+        challenge.restore_refuses_overwrite_works,
+        config.config_get_works,
+    ]
+    # reload the keyspace or toggle server configuration, run one at a time once the rest are
+    # done
+    __serial_fn = [
+        bucket.decrement_survives_reload_works,
+        bucket.aof_works,
+        bucket.defrag_works,
+        config.config_set_works,
+        quarantine.quarantine_works,
     ]
     __tasks = []
 
@@ -50,6 +81,8 @@ class Runner(object):
         await self.__register()
         for task in self.__tasks:
             await task
+        for fn in self.__serial_fn:
+            await fn()
 
     """Runs in separate threads"""
     def __init__(self):